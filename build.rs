@@ -1,5 +1,129 @@
 extern crate peg;
 
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// A package in Cargo.lock
+struct LockedPackage {
+    name: String,
+    version: String,
+    dependencies: Vec<String>,
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_end_matches(',').trim().trim_matches('"').to_owned()
+}
+
+/// The packages in a Cargo.lock, read line by line so that the build script
+/// needs neither a TOML parser nor a `cargo` subprocess
+fn locked_packages(lock: &str) -> Vec<LockedPackage> {
+    let mut packages = Vec::new();
+    let mut in_dependencies = false;
+    for line in lock.lines().map(|line| line.trim()) {
+        if line == "[[package]]" {
+            packages.push(LockedPackage {
+                name: String::new(),
+                version: String::new(),
+                dependencies: Vec::new(),
+            });
+            in_dependencies = false;
+            continue;
+        }
+        let package = match packages.last_mut() {
+            Some(package) => package,
+            None => continue,
+        };
+        if in_dependencies {
+            if line.starts_with(']') {
+                in_dependencies = false;
+            } else if !line.is_empty() {
+                package.dependencies.push(unquote(line));
+            }
+        } else if line.starts_with("name = ") {
+            package.name = unquote(&line["name = ".len()..]);
+        } else if line.starts_with("version = ") {
+            package.version = unquote(&line["version = ".len()..]);
+        } else if line.starts_with("dependencies = [") {
+            in_dependencies = !line.ends_with(']');
+        } else if line.starts_with('[') {
+            in_dependencies = false;
+        }
+    }
+    packages
+}
+
+/// The version of gears this package depends on in a Cargo.lock. A
+/// dependency is listed as `gears`, or as `gears <version> (<source>)` when
+/// the lock holds more than one version of it.
+fn locked_version(lock: &str, package_name: &str) -> Option<String> {
+    let packages = locked_packages(lock);
+    let dependency = packages
+        .iter()
+        .find(|package| package.name == package_name)?
+        .dependencies
+        .iter()
+        .find(|dependency| dependency.split_whitespace().next() == Some("gears"))?;
+    match dependency.split_whitespace().nth(1) {
+        Some(version) => Some(version.to_owned()),
+        None => packages
+            .iter()
+            .find(|package| package.name == "gears")
+            .map(|package| package.version.clone()),
+    }
+}
+
+/// The Cargo.lock of the package or of the workspace it is in
+fn find_lock(manifest_dir: &Path) -> Option<PathBuf> {
+    manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.exists())
+}
+
+fn read(path: &Path) -> Option<String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .ok()
+        .map(|_| contents)
+}
+
+/// The version of the gears crate this build links against, from Cargo.lock
+fn gears_version(manifest_dir: &Path, package_name: &str) -> Option<String> {
+    let lock = find_lock(manifest_dir)?;
+    println!("cargo:rerun-if-changed={}", lock.display());
+    locked_version(&read(&lock)?, package_name)
+}
+
+/// The gears version required in Cargo.toml, for builds without a lock
+fn required_gears_version(manifest_dir: &Path) -> Option<String> {
+    read(&manifest_dir.join("Cargo.toml"))?
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("gears ") || line.starts_with("gears="))
+        .and_then(|line| line.splitn(2, '=').nth(1))
+        .map(|requirement| unquote(requirement).trim_start_matches(|c: char| c == '^' || c == '~' || c == '=').to_owned())
+}
+
 fn main() {
     peg::cargo_build("src/command_grammar.rustpeg");
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_owned()));
+    let package_name = env::var("CARGO_PKG_NAME").unwrap_or_else(|_| "gears-cli".to_owned());
+
+    // Bundles are checked against this version
+    let version = gears_version(&manifest_dir, &package_name)
+        .or_else(|| {
+            println!("cargo:warning=No gears version in Cargo.lock, using the version required in Cargo.toml");
+            required_gears_version(&manifest_dir)
+        })
+        .unwrap_or_else(|| {
+            println!("cargo:warning=Unable to determine the gears version, bundles will not be compatible");
+            "unknown".to_owned()
+        });
+    println!("cargo:rustc-env=GEARS_VERSION={}", version);
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=src/command_grammar.rustpeg");
 }
@@ -0,0 +1,256 @@
+use actix_web::error::PayloadError;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use gears::structure::common::ModelLoadError;
+
+use modelstore::model_executor::StoreError;
+
+pub static CONTENT_TYPE_JSONAPI: &'static str = "application/vnd.api+json";
+
+/// A JSON:API error object
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub status: String,
+    pub code: String,
+    pub title: String,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<ErrorSource>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorSource {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pointer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameter: Option<String>,
+}
+
+/// Turn a validation path such as `body.xflows.0.name` into a JSON pointer
+/// into the model JSON that PUT takes and PATCH applies to
+fn to_pointer(path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return "".to_owned();
+    }
+    let tokens: Vec<String> = path
+        .split(|c: char| c == '.' || c == '/')
+        .map(|token| token.replace("~", "~0"))
+        .collect();
+    format!("/{}", tokens.join("/"))
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &str, title: &str, detail: &str) -> ApiError {
+        ApiError {
+            status: status.as_u16().to_string(),
+            code: code.to_owned(),
+            title: title.to_owned(),
+            detail: detail.to_owned(),
+            source: None,
+        }
+    }
+
+    pub fn not_found(detail: &str) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", "Not found", detail)
+    }
+
+    pub fn method_not_allowed(detail: &str) -> ApiError {
+        ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "Method not allowed", detail)
+    }
+
+    pub fn forbidden(detail: &str) -> ApiError {
+        ApiError::new(StatusCode::FORBIDDEN, "forbidden", "Forbidden", detail)
+    }
+
+    pub fn bad_request(detail: &str) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", "Bad request", detail)
+    }
+
+    /// An invalid query parameter
+    pub fn parameter(name: &str, detail: &str) -> ApiError {
+        let mut err = ApiError::new(StatusCode::BAD_REQUEST, "invalid_parameter", "Invalid query parameter", detail);
+        err.source = Some(ErrorSource {
+            pointer: None,
+            parameter: Some(name.to_owned()),
+        });
+        err
+    }
+
+    /// A sort field the resources do not have
+    pub fn invalid_sort(field: &str) -> ApiError {
+        let mut err = ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_sort",
+            "Invalid sort",
+            &format!("Unknown sort field '{}'", field),
+        );
+        err.source = Some(ErrorSource {
+            pointer: None,
+            parameter: Some("sort".to_owned()),
+        });
+        err
+    }
+
+    /// A model validation error for the given paths in the model
+    pub fn validation(detail: &str, paths: &[String]) -> ApiError {
+        let mut err = ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "validation_failed",
+            "Model validation failed",
+            detail,
+        );
+        err.source = paths.first().map(|path| ErrorSource {
+            pointer: Some(to_pointer(path)),
+            parameter: None,
+        });
+        err
+    }
+
+    pub fn from_load_error(err: &ModelLoadError) -> ApiError {
+        match *err {
+            ModelLoadError::InputError(ref msg) => {
+                let mut err = ApiError::new(StatusCode::BAD_REQUEST, "invalid_input", "Invalid model input", msg);
+                err.source = Some(ErrorSource {
+                    pointer: Some("".to_owned()),
+                    parameter: None,
+                });
+                err
+            }
+            ModelLoadError::BadStructure(ref msg) => ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "bad_structure",
+                "Invalid model structure",
+                msg,
+            ),
+            ref other => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "model_error",
+                "Model error",
+                &format!("{:?}", other),
+            ),
+        }
+    }
+
+    /// The stored model could not be read or written
+    pub fn load_failure(err: &ModelLoadError) -> ApiError {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "model_error",
+            "Model error",
+            &format!("{:?}", err),
+        )
+    }
+
+    /// The request body could not be read
+    pub fn from_payload_error(err: &PayloadError) -> ApiError {
+        match *err {
+            PayloadError::Overflow => ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "Payload too large",
+                &err.to_string(),
+            ),
+            _ => ApiError::bad_request(&format!("Unable to read the request body: {}", err)),
+        }
+    }
+
+    pub fn unavailable(detail: &str) -> ApiError {
+        ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", "Service unavailable", detail)
+    }
+
+    pub fn unsupported_media_type(detail: &str) -> ApiError {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Unsupported media type",
+            detail,
+        )
+    }
+
+    pub fn from_store_error(err: &StoreError) -> Vec<ApiError> {
+        match *err {
+            StoreError::Load(ref err) => vec![ApiError::load_failure(err)],
+            StoreError::Input(ref err) => vec![ApiError::from_load_error(err)],
+            StoreError::NotFound(ref msg) => vec![ApiError::not_found(msg)],
+            StoreError::Unsupported(ref msg) => vec![ApiError::method_not_allowed(msg)],
+            StoreError::RevisionMismatch { ref current, .. } => vec![ApiError::new(
+                StatusCode::PRECONDITION_FAILED,
+                "revision_mismatch",
+                "Model has changed",
+                &format!("The model is now at revision \"{}\", which does not match If-Match", current),
+            )],
+            StoreError::Patch(ref msg) => vec![ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "patch_failed",
+                "Patch could not be applied",
+                msg,
+            )],
+            StoreError::Invalid(ref failures) => failures
+                .iter()
+                .map(|failure| ApiError::validation(&failure.message, &failure.paths))
+                .collect(),
+        }
+    }
+
+    pub fn response(self) -> HttpResponse {
+        error_response(vec![self])
+    }
+}
+
+/// A JSON:API error document, with the status of the first error
+pub fn error_response(errors: Vec<ApiError>) -> HttpResponse {
+    let status = errors
+        .first()
+        .and_then(|err| err.status.parse::<u16>().ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::BAD_REQUEST);
+    let body = json!({ "errors": errors });
+
+    HttpResponse::build(status)
+        .content_type(CONTENT_TYPE_JSONAPI)
+        .body(body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation_paths_become_pointers() {
+        assert_eq!(to_pointer(""), "");
+        assert_eq!(to_pointer("body.xflows.0.name"), "/body/xflows/0/name");
+        assert_eq!(to_pointer("/body/a~b"), "/body/a~0b");
+    }
+
+    #[test]
+    fn load_errors_are_mapped_by_kind() {
+        let input = ApiError::from_load_error(&ModelLoadError::InputError("bad json".to_owned()));
+        assert_eq!((input.status.as_str(), input.code.as_str()), ("400", "invalid_input"));
+        let structure = ApiError::from_load_error(&ModelLoadError::BadStructure("no body".to_owned()));
+        assert_eq!((structure.status.as_str(), structure.code.as_str()), ("422", "bad_structure"));
+    }
+
+    #[test]
+    fn payload_errors_are_mapped_by_kind() {
+        assert_eq!(ApiError::from_payload_error(&PayloadError::Overflow).status, "413");
+        assert_eq!(ApiError::from_payload_error(&PayloadError::EncodingCorrupted).status, "400");
+    }
+
+    #[test]
+    fn store_errors_are_mapped_by_kind() {
+        let mismatch = StoreError::RevisionMismatch {
+            expected: vec!["a".to_owned()],
+            current: "b".to_owned(),
+        };
+        assert_eq!(ApiError::from_store_error(&mismatch)[0].status, "412");
+        let load = StoreError::Load(ModelLoadError::InputError("unreadable".to_owned()));
+        assert_eq!(ApiError::from_store_error(&load)[0].status, "500");
+        let input = StoreError::Input(ModelLoadError::InputError("bad json".to_owned()));
+        assert_eq!(ApiError::from_store_error(&input)[0].status, "400");
+        assert_eq!(ApiError::from_store_error(&StoreError::NotFound("no revision".to_owned()))[0].status, "404");
+        assert_eq!(ApiError::from_store_error(&StoreError::Unsupported("no delete".to_owned()))[0].status, "405");
+        assert_eq!(ApiError::from_store_error(&StoreError::Patch("no path".to_owned()))[0].status, "422");
+        assert!(ApiError::from_store_error(&StoreError::Invalid(Vec::new())).is_empty());
+    }
+}
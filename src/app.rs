@@ -2,6 +2,9 @@
 pub enum Format {
     JSON,
     YAML,
+    TOML,
+    MSGPACK,
+    NDJSON,
 }
 
 #[derive(Debug)]
@@ -10,12 +13,79 @@ pub struct AppState {
     pub path_config: String,
     pub path_in: String,
     pub path_out: String,
-    pub format_in: Format,
+    pub format_in: Option<Format>,
     pub format_out: Format,
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub backup: bool,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct ServerConfig {
     pub enable_opa: bool,
     pub opa_url: Option<String>,
+    /// Bearer token of privileged callers, who may force updates that fail
+    /// validation
+    pub admin_token: Option<String>,
+    pub cors: Option<CorsConfig>,
+    pub auth: Option<AuthConfig>,
+}
+
+/// Cross-origin requests allowed by the server. Empty lists, or an origin of
+/// `*`, allow anything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Seconds a preflight response may be cached for
+    pub max_age: Option<usize>,
+}
+
+/// Verification of JWT bearer tokens. Without any roles configured, every
+/// caller with a valid token may read and change models.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Shared secret of HS256 tokens
+    pub secret: Option<String>,
+    /// Local JWKS file with the keys of RS256 tokens
+    pub jwks: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// Claim holding the user name, dotted for nested claims
+    pub user_claim: String,
+    /// Claim holding the roles, a list or a space separated string
+    pub roles_claim: String,
+    /// Roles that may read models
+    pub read_roles: Vec<String>,
+    /// Roles that may read and change models
+    pub write_roles: Vec<String>,
+    /// Roles that may also force updates that fail validation
+    pub admin_roles: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            secret: None,
+            jwks: None,
+            issuer: None,
+            audience: None,
+            user_claim: "sub".to_owned(),
+            roles_claim: "roles".to_owned(),
+            read_roles: Vec::new(),
+            write_roles: Vec::new(),
+            admin_roles: Vec::new(),
+        }
+    }
+}
+
+/// The project config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProjectConfig {
+    pub server: ServerConfig,
 }
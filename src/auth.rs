@@ -0,0 +1,366 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::{self, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use apierror::ApiError;
+use app::AuthConfig;
+
+/// The caller of a request, from a verified bearer token
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub user: String,
+    pub roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(default)]
+    n: String,
+    #[serde(default)]
+    e: String,
+}
+
+struct Inner {
+    config: AuthConfig,
+    rsa_keys: Vec<Jwk>,
+}
+
+/// Middleware verifying JWT bearer tokens, HS256 with a shared secret or
+/// RS256 with the keys of a local JWKS file, and checking the roles of the
+/// caller against the request method
+#[derive(Clone)]
+pub struct JwtAuth {
+    inner: Arc<Inner>,
+}
+
+fn read_jwks(path: &str) -> ::std::result::Result<Vec<Jwk>, String> {
+    let file = File::open(path).map_err(|err| format!("unable to open '{}', {}", path, err))?;
+    let set: JwkSet = serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| format!("unable to read '{}', {}", path, err))?;
+    let keys: Vec<Jwk> = set.keys.into_iter().filter(|key| key.kty == "RSA").collect();
+    if keys.is_empty() {
+        return Err(format!("'{}' has no RSA keys", path));
+    }
+    Ok(keys)
+}
+
+/// A claim by name, where a dotted name such as `realm_access.roles` looks
+/// into nested objects
+fn claim<'a>(claims: &'a Value, name: &str) -> Option<&'a Value> {
+    name.split('.')
+        .fold(Some(claims), |value, key| value.and_then(|value| value.get(key)))
+}
+
+/// Roles from a list of strings, or a space separated string like `scope`
+fn roles_of(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(&Value::Array(ref items)) => items
+            .iter()
+            .filter_map(|item| item.as_str())
+            .map(|role| role.to_owned())
+            .collect(),
+        Some(&Value::String(ref roles)) => roles.split_whitespace().map(|role| role.to_owned()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn has_role(identity: &Identity, roles: &[String]) -> bool {
+    identity.roles.iter().any(|role| roles.contains(role))
+}
+
+fn unauthorized(detail: &str) -> HttpResponse {
+    let mut response = ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized", detail).response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+impl JwtAuth {
+    pub fn new(config: &AuthConfig) -> ::std::result::Result<JwtAuth, String> {
+        if config.secret.is_none() && config.jwks.is_none() {
+            return Err("either a secret or a JWKS file is required".to_owned());
+        }
+        let rsa_keys = match config.jwks {
+            Some(ref path) => read_jwks(path)?,
+            None => Vec::new(),
+        };
+        Ok(JwtAuth {
+            inner: Arc::new(Inner {
+                config: config.clone(),
+                rsa_keys: rsa_keys,
+            }),
+        })
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let config = &self.inner.config;
+        let mut validation = Validation::new(algorithm);
+        validation.iss = config.issuer.clone();
+        if let Some(ref audience) = config.audience {
+            validation.set_audience(&[audience]);
+        }
+        validation
+    }
+
+    /// Verify a token, only with the algorithms that are configured
+    fn verify(&self, token: &str) -> ::std::result::Result<Value, String> {
+        let header = decode_header(token).map_err(|err| format!("{}", err))?;
+        let config = &self.inner.config;
+
+        match (header.alg, &config.secret) {
+            (Algorithm::HS256, &Some(ref secret)) => decode::<Value>(
+                token,
+                &DecodingKey::from_secret(secret.as_bytes()),
+                &self.validation(Algorithm::HS256),
+            )
+            .map(|data| data.claims)
+            .map_err(|err| format!("{}", err)),
+            (Algorithm::RS256, _) if !self.inner.rsa_keys.is_empty() => {
+                let validation = self.validation(Algorithm::RS256);
+                let mut result = Err(format!("no key matches kid {:?}", header.kid));
+                for key in &self.inner.rsa_keys {
+                    if header.kid.is_some() && key.kid != header.kid {
+                        continue;
+                    }
+                    result = decode::<Value>(token, &DecodingKey::from_rsa_components(&key.n, &key.e), &validation)
+                        .map(|data| data.claims)
+                        .map_err(|err| format!("{}", err));
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                result
+            }
+            (alg, _) => Err(format!("{:?} tokens are not accepted", alg)),
+        }
+    }
+
+    fn identity(&self, claims: &Value) -> Identity {
+        let config = &self.inner.config;
+        Identity {
+            user: claim(claims, &config.user_claim)
+                .and_then(|user| user.as_str())
+                .unwrap_or("anonymous")
+                .to_owned(),
+            roles: roles_of(claim(claims, &config.roles_claim)),
+        }
+    }
+}
+
+/// Whether no roles are configured, so that every verified caller may do
+/// everything
+fn without_roles(config: &AuthConfig) -> bool {
+    config.read_roles.is_empty() && config.write_roles.is_empty() && config.admin_roles.is_empty()
+}
+
+/// Whether the identity may make a request with this method. Safe methods
+/// need a read, write or admin role, others a write or admin role.
+fn allowed(config: &AuthConfig, identity: &Identity, method: &Method) -> bool {
+    if without_roles(config) {
+        return true;
+    }
+    let write = has_role(identity, &config.write_roles) || has_role(identity, &config.admin_roles);
+    write || (method.is_safe() && has_role(identity, &config.read_roles))
+}
+
+/// The token in the Authorization header, without its `Bearer ` prefix
+pub fn bearer_token<S>(req: &HttpRequest<S>) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| {
+            if val.starts_with("Bearer ") {
+                Some(val["Bearer ".len()..].trim())
+            } else {
+                None
+            }
+        })
+}
+
+/// Compare two secrets in a time that depends only on their lengths, so that
+/// a caller cannot find a token byte by byte
+pub fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+    left.iter().zip(right.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The bearer token of a request. Browsers cannot set headers on an
+/// `EventSource`, so the change feed also takes it as `access_token`.
+fn token_of<S>(req: &HttpRequest<S>) -> Option<String> {
+    let header = bearer_token(req).map(|token| token.to_owned());
+    if header.is_some() {
+        return header;
+    }
+    if *req.method() == Method::GET && req.path().ends_with("/changes") {
+        return req.query().get("access_token").cloned();
+    }
+    None
+}
+
+/// Whether the identity has one of the admin roles, or no roles are
+/// configured at all
+pub fn is_admin(config: &AuthConfig, identity: &Identity) -> bool {
+    without_roles(config) || has_role(identity, &config.admin_roles)
+}
+
+impl<S> Middleware<S> for JwtAuth {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let token = match token_of(req) {
+            Some(token) => token,
+            None => return Ok(Started::Response(unauthorized("A bearer token is required"))),
+        };
+
+        let claims = match self.verify(&token) {
+            Ok(claims) => claims,
+            Err(err) => {
+                debug!("auth: rejected token, {}", err);
+                return Ok(Started::Response(unauthorized(&format!("Invalid token, {}", err))));
+            }
+        };
+
+        let identity = self.identity(&claims);
+        if !allowed(&self.inner.config, &identity, req.method()) {
+            info!("auth: {} is not allowed to {} '{}'", identity.user, req.method(), req.path());
+            return Ok(Started::Response(
+                ApiError::forbidden(&format!("{} is not allowed on '{}'", req.method(), req.path())).response(),
+            ));
+        }
+        req.extensions_mut().insert(identity);
+        Ok(Started::Done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn identity(roles: &[&str]) -> Identity {
+        Identity {
+            user: "alice".to_owned(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    fn roles(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn hs256_auth() -> JwtAuth {
+        let mut config = AuthConfig::default();
+        config.secret = Some("secret".to_owned());
+        config.user_claim = "preferred_username".to_owned();
+        config.roles_claim = "realm_access.roles".to_owned();
+        JwtAuth::new(&config).unwrap()
+    }
+
+    fn token(secret: &str, claims: &Value) -> String {
+        encode(&Header::default(), claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn without_roles_every_caller_is_allowed() {
+        let config = AuthConfig::default();
+        assert!(allowed(&config, &identity(&[]), &Method::GET));
+        assert!(allowed(&config, &identity(&[]), &Method::DELETE));
+        assert!(is_admin(&config, &identity(&[])));
+    }
+
+    #[test]
+    fn only_admin_roles_may_force_once_roles_are_configured() {
+        let mut config = AuthConfig::default();
+        config.write_roles = roles(&["editor"]);
+        assert!(!is_admin(&config, &identity(&["editor"])));
+
+        config.admin_roles = roles(&["admin"]);
+        assert!(is_admin(&config, &identity(&["admin"])));
+    }
+
+    #[test]
+    fn read_roles_are_read_only() {
+        let mut config = AuthConfig::default();
+        config.read_roles = roles(&["viewer"]);
+
+        assert!(allowed(&config, &identity(&["viewer"]), &Method::GET));
+        assert!(!allowed(&config, &identity(&["viewer"]), &Method::PUT));
+        assert!(!allowed(&config, &identity(&["viewer"]), &Method::PATCH));
+        assert!(!allowed(&config, &identity(&["viewer"]), &Method::DELETE));
+        assert!(!allowed(&config, &identity(&["other"]), &Method::GET));
+    }
+
+    #[test]
+    fn write_and_admin_roles_may_change_models() {
+        let mut config = AuthConfig::default();
+        config.read_roles = roles(&["viewer"]);
+        config.write_roles = roles(&["editor"]);
+        config.admin_roles = roles(&["admin"]);
+
+        assert!(allowed(&config, &identity(&["editor"]), &Method::GET));
+        assert!(allowed(&config, &identity(&["editor"]), &Method::PUT));
+        assert!(allowed(&config, &identity(&["admin"]), &Method::DELETE));
+        assert!(!allowed(&config, &identity(&["viewer"]), &Method::POST));
+        assert!(is_admin(&config, &identity(&["admin"])));
+        assert!(!is_admin(&config, &identity(&["editor"])));
+    }
+
+    #[test]
+    fn roles_from_lists_and_strings() {
+        let claims = json!({"realm_access": {"roles": ["a", "b"]}, "scope": "read write"});
+        assert_eq!(roles_of(claim(&claims, "realm_access.roles")), roles(&["a", "b"]));
+        assert_eq!(roles_of(claim(&claims, "scope")), roles(&["read", "write"]));
+        assert!(roles_of(claim(&claims, "missing.roles")).is_empty());
+    }
+
+    #[test]
+    fn hs256_tokens_are_verified() {
+        let auth = hs256_auth();
+        let claims = json!({
+            "preferred_username": "alice",
+            "realm_access": {"roles": ["editor"]},
+            "exp": 4102444800u64
+        });
+
+        let verified = auth.verify(&token("secret", &claims)).unwrap();
+        let identity = auth.identity(&verified);
+        assert_eq!(identity.user, "alice");
+        assert_eq!(identity.roles, roles(&["editor"]));
+
+        assert!(auth.verify(&token("other", &claims)).is_err());
+    }
+
+    #[test]
+    fn expired_tokens_and_tokens_without_expiry_are_rejected() {
+        let auth = hs256_auth();
+        assert!(auth.verify(&token("secret", &json!({"exp": 1}))).is_err());
+        assert!(auth.verify(&token("secret", &json!({"sub": "alice"}))).is_err());
+    }
+
+    #[test]
+    fn a_secret_or_jwks_is_required() {
+        assert!(JwtAuth::new(&AuthConfig::default()).is_err());
+    }
+
+    #[test]
+    fn secrets_are_compared_whole() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
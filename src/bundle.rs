@@ -0,0 +1,281 @@
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use gears::structure::common::ModelLoadError;
+use gears::structure::gxmodel::GxModel;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use tar;
+
+use diff::{key_of, model_to_value};
+use util;
+
+pub static BUNDLE_VERSION: i64 = 1;
+pub static GEARS_VERSION: &'static str = env!("GEARS_VERSION");
+
+static MANIFEST_PATH: &'static str = "manifest.json";
+static MODEL_PATH: &'static str = "model.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub bundle_version: i64,
+    pub model_id: String,
+    pub model_version: Value,
+    pub gears_version: String,
+    pub created_by: String,
+    /// Checksum of the model header
+    pub model_sha256: String,
+    pub documents: Vec<ManifestDocument>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestDocument {
+    pub path: String,
+    pub kind: String,
+    pub id: String,
+    /// Whether the document is one of a list, such as an xflow, or a single
+    /// document such as the domain
+    pub collection: bool,
+    pub sha256: String,
+}
+
+fn bundle_error<E: ::std::fmt::Debug>(err: E) -> ModelLoadError {
+    ModelLoadError::InputError(format!("{:?}", err))
+}
+
+fn bundle_invalid(msg: String) -> ModelLoadError {
+    ModelLoadError::BadStructure(msg)
+}
+
+fn checksum(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// The contents of a file in a bundle, if its checksum matches
+fn checked<'a>(files: &'a HashMap<String, Vec<u8>>, path: &str, sha256: &str) -> Result<&'a [u8], ModelLoadError> {
+    let data = match files.get(path) {
+        Some(data) => data,
+        None => return Err(bundle_invalid(format!("Missing document '{}'", path))),
+    };
+    if checksum(data) != sha256 {
+        return Err(bundle_invalid(format!("Checksum mismatch for document '{}'", path)));
+    }
+    Ok(data.as_slice())
+}
+
+/// Version strings are compatible when their major and minor versions match,
+/// following the semver rules for 0.x releases
+pub fn is_compatible(version: &str, other: &str) -> bool {
+    let release = |version: &str| -> Vec<String> {
+        version.split('.').take(2).map(|part| part.to_owned()).collect()
+    };
+    release(version) == release(other)
+}
+
+pub struct SplitDocument {
+    pub kind: String,
+    pub id: String,
+    pub collection: bool,
+    pub doc: Value,
+}
+
+/// Split a model into its header, the model without its documents, and the
+/// documents in its body
+pub fn split_documents(model: &GxModel) -> (Value, Vec<SplitDocument>) {
+    let mut header = model_to_value(model);
+    let mut documents = Vec::new();
+
+    if let Some(body) = header.get_mut("body").and_then(|body| body.as_object_mut()) {
+        for (kind, docs) in body.iter_mut() {
+            let collection = docs.is_array();
+            let entries = match mem::replace(docs, Value::Null) {
+                Value::Array(docs) => docs,
+                doc => vec![doc],
+            };
+            if collection {
+                *docs = Value::Array(Vec::new());
+            }
+
+            for doc in entries {
+                documents.push(SplitDocument {
+                    kind: kind.clone(),
+                    id: key_of(&doc, "id"),
+                    collection: collection,
+                    doc: doc,
+                });
+            }
+        }
+
+        let single: Vec<String> = body
+            .iter()
+            .filter(|&(_, docs)| !docs.is_array())
+            .map(|(kind, _)| kind.clone())
+            .collect();
+        for kind in single {
+            body.remove(&kind);
+        }
+    }
+
+    (header, documents)
+}
+
+/// Write a model as a gzipped tar archive holding a manifest, the model
+/// header and one file per document
+pub fn write(model: &GxModel, path: &str, backup: bool) -> Result<(), ModelLoadError> {
+    let (header, split) = split_documents(model);
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut documents = Vec::new();
+
+    for document in split {
+        let doc_path = if document.collection {
+            format!("documents/{}/{}.json", document.kind, document.id)
+        } else {
+            format!("documents/{}.json", document.kind)
+        };
+        let data = serde_json::to_vec_pretty(&document.doc).map_err(bundle_error)?;
+        documents.push(ManifestDocument {
+            path: doc_path.clone(),
+            kind: document.kind,
+            id: document.id,
+            collection: document.collection,
+            sha256: checksum(&data),
+        });
+        files.push((doc_path, data));
+    }
+
+    let header_data = serde_json::to_vec_pretty(&header).map_err(bundle_error)?;
+    let manifest = Manifest {
+        bundle_version: BUNDLE_VERSION,
+        model_id: model.id.to_string(),
+        model_version: header.get("version").cloned().unwrap_or(Value::Null),
+        gears_version: GEARS_VERSION.to_owned(),
+        created_by: format!("gears-cli {}", env!("CARGO_PKG_VERSION")),
+        model_sha256: checksum(&header_data),
+        documents: documents,
+    };
+
+    files.insert(0, (MODEL_PATH.to_owned(), header_data));
+    files.insert(
+        0,
+        (
+            MANIFEST_PATH.to_owned(),
+            serde_json::to_vec_pretty(&manifest).map_err(bundle_error)?,
+        ),
+    );
+
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), flate2::Compression::default()));
+    for (path, data) in files {
+        let mut entry = tar::Header::new_gnu();
+        entry.set_size(data.len() as u64);
+        entry.set_mode(0o644);
+        entry.set_cksum();
+        archive
+            .append_data(&mut entry, &path, data.as_slice())
+            .map_err(bundle_error)?;
+    }
+    let data = archive
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .map_err(bundle_error)?;
+
+    util::write_file(path, data, backup).map_err(bundle_error)
+}
+
+/// Read a bundle, verifying the checksum of every document and that it was
+/// written with a compatible version of gears
+pub fn read(path: &str) -> Result<GxModel, ModelLoadError> {
+    let file = File::open(path).map_err(bundle_error)?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+
+    for entry in archive.entries().map_err(bundle_error)? {
+        let mut entry = entry.map_err(bundle_error)?;
+        let name = entry.path().map_err(bundle_error)?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(bundle_error)?;
+        files.insert(name, data);
+    }
+
+    let manifest: Manifest = match files.get(MANIFEST_PATH) {
+        Some(data) => serde_json::from_slice(data).map_err(bundle_error)?,
+        None => return Err(bundle_invalid("Bundle has no manifest".to_owned())),
+    };
+
+    if manifest.bundle_version > BUNDLE_VERSION {
+        return Err(bundle_invalid(format!(
+            "Bundle version {} is newer than the supported version {}",
+            manifest.bundle_version, BUNDLE_VERSION
+        )));
+    }
+    if !is_compatible(&manifest.gears_version, GEARS_VERSION) {
+        return Err(bundle_invalid(format!(
+            "Bundle was written with gears {}, which is incompatible with gears {}",
+            manifest.gears_version, GEARS_VERSION
+        )));
+    }
+
+    let data = checked(&files, MODEL_PATH, &manifest.model_sha256)?;
+    let mut model: Value = serde_json::from_slice(data).map_err(bundle_error)?;
+
+    for document in &manifest.documents {
+        let data = checked(&files, &document.path, &document.sha256)?;
+        let doc: Value = serde_json::from_slice(data).map_err(bundle_error)?;
+        let target = &mut model["body"][document.kind.as_str()];
+        if document.collection {
+            if !target.is_array() {
+                *target = Value::Array(Vec::new());
+            }
+            if let Some(docs) = target.as_array_mut() {
+                docs.push(doc);
+            }
+        } else {
+            *target = doc;
+        }
+    }
+
+    GxModel::from_json(&model.to_string()).map_err(bundle_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn versions_are_compatible_within_a_minor_release() {
+        assert!(is_compatible("0.1.7", "0.1.9"));
+        assert!(is_compatible("1.2.0", "1.2.3"));
+        assert!(!is_compatible("0.1.7", "0.2.0"));
+        assert!(!is_compatible("1.2.0", "2.2.0"));
+    }
+
+    #[test]
+    fn files_are_checked_against_their_checksum() {
+        let mut files = HashMap::new();
+        files.insert(MODEL_PATH.to_owned(), b"{}".to_vec());
+        let sha256 = checksum(b"{}");
+
+        assert_eq!(checked(&files, MODEL_PATH, &sha256).unwrap(), &b"{}"[..]);
+        assert!(checked(&files, MODEL_PATH, &checksum(b"[]")).is_err());
+        assert!(checked(&files, "documents/domain.json", &sha256).is_err());
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let model = fixtures::model();
+        let path = env::temp_dir().join(format!("gears-cli-bundle-{}.gxb", process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        write(&model, &path, false).unwrap();
+        let loaded = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(model_to_value(&loaded), model_to_value(&model));
+    }
+}
@@ -0,0 +1,104 @@
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use zstd;
+
+use util;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &str) -> Compression {
+        if path.ends_with(".gz") {
+            Compression::Gzip
+        } else if path.ends_with(".zst") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// The path without a compression extension, so `model.yaml.gz` gives
+/// `model.yaml` for format detection
+pub fn strip_extension(path: &str) -> &str {
+    match Compression::from_path(path) {
+        Compression::Gzip => &path[..path.len() - ".gz".len()],
+        Compression::Zstd => &path[..path.len() - ".zst".len()],
+        Compression::None => path,
+    }
+}
+
+/// Open a file for reading, decompressing it if its extension is `.gz` or
+/// `.zst`
+pub fn open(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    Ok(match Compression::from_path(path) {
+        Compression::Gzip => Box::new(BufReader::new(GzDecoder::new(file))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::stream::Decoder::new(file)?)),
+        Compression::None => Box::new(BufReader::new(file)),
+    })
+}
+
+/// Atomically write a file, with `write` streaming into a compressor if the
+/// extension is `.gz` or `.zst`
+pub fn write_with<F>(path: &str, backup: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let compression = Compression::from_path(path);
+    util::write_file_with(path, backup, |file| match compression {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+            write(&mut encoder)?;
+            encoder.finish().map(|_| ())
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::Encoder::new(file, 0)?;
+            write(&mut encoder)?;
+            encoder.finish().map(|_| ())
+        }
+        Compression::None => write(file),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::process;
+
+    #[test]
+    fn strip_extension_keeps_the_format_extension() {
+        assert_eq!(strip_extension("model.yaml.gz"), "model.yaml");
+        assert_eq!(strip_extension("model.json.zst"), "model.json");
+        assert_eq!(strip_extension("model.toml"), "model.toml");
+    }
+
+    #[test]
+    fn write_with_round_trips_through_open() {
+        let dir = env::temp_dir().join(format!("gears-cli-compress-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = "{\"id\": \"model\"}\n".repeat(1000);
+
+        for name in &["model.json", "model.json.gz", "model.json.zst"] {
+            let path = dir.join(name);
+            let path = path.to_str().unwrap();
+            write_with(path, false, |writer| writer.write_all(data.as_bytes())).unwrap();
+
+            let mut contents = String::new();
+            open(path).unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, data, "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+use gears::structure::gxmodel::GxModel;
+use serde_json::{self, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub item: String,
+    pub path: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sigil = match self.kind {
+            ChangeKind::Added => "+",
+            ChangeKind::Removed => "-",
+            ChangeKind::Changed => "~",
+        };
+        write!(f, "{} {} '{}'", sigil, self.item, self.path)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelDiff {
+    pub left: String,
+    pub right: String,
+    pub changes: Vec<Change>,
+}
+
+impl ModelDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Compare two models semantically, by entity, attribute, xflow, xflow node and
+/// edge, page and translation key, rather than by their serialized text
+pub fn diff_models(left: &GxModel, right: &GxModel) -> ModelDiff {
+    let left_val = model_to_value(left);
+    let right_val = model_to_value(right);
+    let mut changes = Vec::new();
+
+    diff_entities(&left_val, &right_val, &mut changes);
+    diff_xflows(&left_val, &right_val, &mut changes);
+    diff_pages(&left_val, &right_val, &mut changes);
+    diff_translations(&left_val, &right_val, &mut changes);
+
+    ModelDiff {
+        left: left.id.to_string(),
+        right: right.id.to_string(),
+        changes: changes,
+    }
+}
+
+pub fn model_to_value(model: &GxModel) -> Value {
+    serde_json::to_value(model).unwrap_or(Value::Null)
+}
+
+/// Index the array at `pointer` by the value of `key` in each of its items
+pub fn keyed<'a>(doc: &'a Value, pointer: &str, key: &str) -> BTreeMap<String, &'a Value> {
+    let mut map = BTreeMap::new();
+    if let Some(items) = doc.pointer(pointer).and_then(|list| list.as_array()) {
+        for item in items {
+            map.insert(key_of(item, key), item);
+        }
+    }
+    map
+}
+
+/// Index the array at `pointer` by the serialized form of each item, for
+/// values such as xflow edges that have no identifying field
+pub fn keyed_by_value<'a>(doc: &'a Value, pointer: &str) -> BTreeMap<String, &'a Value> {
+    let mut map = BTreeMap::new();
+    if let Some(items) = doc.pointer(pointer).and_then(|list| list.as_array()) {
+        for item in items {
+            map.insert(item.to_string(), item);
+        }
+    }
+    map
+}
+
+pub fn key_of(item: &Value, key: &str) -> String {
+    match item.get(key) {
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+        None => item.to_string(),
+    }
+}
+
+pub fn label_of(id: &str, doc: &Value) -> String {
+    match doc.get("name").and_then(|name| name.as_str()) {
+        Some(name) => format!("{} ({})", name, id),
+        None => id.to_owned(),
+    }
+}
+
+fn push(changes: &mut Vec<Change>, kind: ChangeKind, item: &str, path: String) {
+    changes.push(Change {
+        kind: kind,
+        item: item.to_owned(),
+        path: path,
+    });
+}
+
+/// Report added and removed keys, and call `nested` for keys present on both
+/// sides whose values differ
+fn diff_keyed<F>(
+    item: &str,
+    left: &BTreeMap<String, &Value>,
+    right: &BTreeMap<String, &Value>,
+    changes: &mut Vec<Change>,
+    label: &dyn Fn(&str, &Value) -> String,
+    mut nested: F,
+) where
+    F: FnMut(&str, &Value, &Value, &mut Vec<Change>),
+{
+    for (key, val) in left {
+        if !right.contains_key(key) {
+            push(changes, ChangeKind::Removed, item, label(key, val));
+        }
+    }
+    for (key, val) in right {
+        match left.get(key) {
+            None => push(changes, ChangeKind::Added, item, label(key, val)),
+            Some(left_val) => {
+                if *left_val != *val {
+                    push(changes, ChangeKind::Changed, item, label(key, val));
+                    nested(key, left_val, val, changes);
+                }
+            }
+        }
+    }
+}
+
+fn diff_entities(left: &Value, right: &Value, changes: &mut Vec<Change>) {
+    let pointer = "/body/domain/body/entities";
+    diff_keyed(
+        "entity",
+        &keyed(left, pointer, "name"),
+        &keyed(right, pointer, "name"),
+        changes,
+        &|key, _| key.to_owned(),
+        |entity, left, right, changes| {
+            diff_keyed(
+                "attribute",
+                &keyed(left, "/attributes", "name"),
+                &keyed(right, "/attributes", "name"),
+                changes,
+                &|key, _| format!("{}.{}", entity, key),
+                |_, _, _, _| {},
+            );
+        },
+    );
+}
+
+fn diff_xflows(left: &Value, right: &Value, changes: &mut Vec<Change>) {
+    let pointer = "/body/xflows";
+    diff_keyed(
+        "xflow",
+        &keyed(left, pointer, "id"),
+        &keyed(right, pointer, "id"),
+        changes,
+        &label_of,
+        |_, left, right, changes| {
+            let xflow = label_of(&key_of(right, "id"), right);
+            diff_keyed(
+                "xflow node",
+                &keyed(left, "/body/nodes", "id"),
+                &keyed(right, "/body/nodes", "id"),
+                changes,
+                &|key, _| format!("{} node {}", xflow, key),
+                |_, _, _, _| {},
+            );
+            diff_keyed(
+                "xflow edge",
+                &keyed_by_value(left, "/body/edges"),
+                &keyed_by_value(right, "/body/edges"),
+                changes,
+                &|key, _| format!("{} edge {}", xflow, key),
+                |_, _, _, _| {},
+            );
+        },
+    );
+}
+
+fn diff_pages(left: &Value, right: &Value, changes: &mut Vec<Change>) {
+    let pointer = "/body/pages";
+    diff_keyed(
+        "page",
+        &keyed(left, pointer, "id"),
+        &keyed(right, pointer, "id"),
+        changes,
+        &label_of,
+        |_, _, _, _| {},
+    );
+}
+
+fn diff_translations(left: &Value, right: &Value, changes: &mut Vec<Change>) {
+    let pointer = "/body/translations";
+    let left_docs = keyed(left, pointer, "id");
+    let right_docs = keyed(right, pointer, "id");
+
+    let ids: BTreeSet<&String> = left_docs.keys().chain(right_docs.keys()).collect();
+    for id in ids {
+        let left_doc = left_docs.get(id).cloned();
+        let right_doc = right_docs.get(id).cloned();
+        let locale = right_doc
+            .or(left_doc)
+            .and_then(|doc| doc.pointer("/body/locale"))
+            .and_then(|locale| locale.as_str())
+            .unwrap_or(id.as_str())
+            .to_owned();
+        diff_keyed(
+            "translation key",
+            &translation_items(left_doc),
+            &translation_items(right_doc),
+            changes,
+            &|key, _| format!("{}:{}", locale, key),
+            |_, _, _, _| {},
+        );
+    }
+}
+
+fn translation_items<'a>(doc: Option<&'a Value>) -> BTreeMap<String, &'a Value> {
+    let mut map = BTreeMap::new();
+    if let Some(items) = doc
+        .and_then(|doc| doc.pointer("/body/items"))
+        .and_then(|items| items.as_object())
+    {
+        for (key, val) in items {
+            map.insert(key.clone(), val);
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes<F>(diff: F, left: &Value, right: &Value) -> Vec<String>
+    where
+        F: Fn(&Value, &Value, &mut Vec<Change>),
+    {
+        let mut changes = Vec::new();
+        diff(left, right, &mut changes);
+        changes.iter().map(|change| change.to_string()).collect()
+    }
+
+    fn entities(entities: Value) -> Value {
+        json!({"body": {"domain": {"body": {"entities": entities}}}})
+    }
+
+    #[test]
+    fn entities_and_attributes_are_compared_by_name() {
+        let left = entities(json!([
+            {"name": "item", "attributes": [{"name": "title"}, {"name": "price"}]},
+            {"name": "order", "attributes": []},
+        ]));
+        let right = entities(json!([
+            {"name": "item", "attributes": [{"name": "title"}, {"name": "stock"}]},
+            {"name": "customer", "attributes": []},
+        ]));
+        assert_eq!(
+            changes(diff_entities, &left, &right),
+            vec![
+                "- entity 'order'",
+                "+ entity 'customer'",
+                "~ entity 'item'",
+                "- attribute 'item.price'",
+                "+ attribute 'item.stock'",
+            ]
+        );
+        assert!(changes(diff_entities, &left, &left).is_empty());
+    }
+
+    #[test]
+    fn xflow_nodes_and_edges_are_compared() {
+        let left = json!({"body": {"xflows": [{
+            "id": "x1",
+            "name": "login",
+            "body": {"nodes": [{"id": 1}, {"id": 2}], "edges": [[1, 2]]},
+        }]}});
+        let right = json!({"body": {"xflows": [{
+            "id": "x1",
+            "name": "login",
+            "body": {"nodes": [{"id": 1}, {"id": 3}], "edges": [[1, 3]]},
+        }]}});
+        assert_eq!(
+            changes(diff_xflows, &left, &right),
+            vec![
+                "~ xflow 'login (x1)'",
+                "- xflow node 'login (x1) node 2'",
+                "+ xflow node 'login (x1) node 3'",
+                "- xflow edge 'login (x1) edge [1,2]'",
+                "+ xflow edge 'login (x1) edge [1,3]'",
+            ]
+        );
+    }
+
+    #[test]
+    fn translation_keys_are_compared_per_locale() {
+        let left = json!({"body": {"translations": [
+            {"id": "t1", "body": {"locale": "nl_NL", "items": {"title": "Titel", "save": "Opslaan"}}},
+        ]}});
+        let right = json!({"body": {"translations": [
+            {"id": "t1", "body": {"locale": "nl_NL", "items": {"title": "Kop", "cancel": "Annuleren"}}},
+        ]}});
+        assert_eq!(
+            changes(diff_translations, &left, &right),
+            vec![
+                "- translation key 'nl_NL:save'",
+                "+ translation key 'nl_NL:cancel'",
+                "~ translation key 'nl_NL:title'",
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_and_labels() {
+        assert_eq!(key_of(&json!({"id": "a"}), "id"), "a");
+        assert_eq!(key_of(&json!({"id": 7}), "id"), "7");
+        assert_eq!(label_of("x1", &json!({"name": "login"})), "login (x1)");
+        assert_eq!(label_of("x1", &json!({})), "x1");
+    }
+}
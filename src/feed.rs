@@ -0,0 +1,199 @@
+use bytes::Bytes;
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use gears::structure::gxmodel::GxModel;
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bundle::split_documents;
+
+/// A document of a model that was added, updated or removed
+#[derive(Debug, Serialize)]
+pub struct ChangeEvent {
+    pub model_id: String,
+    pub kind: String,
+    pub id: String,
+    pub action: String,
+    pub revision: String,
+}
+
+struct FeedState {
+    /// The senders of the event streams, with the id of the model they follow
+    subscribers: Vec<(String, UnboundedSender<Bytes>)>,
+    revision: Option<String>,
+    documents: BTreeMap<(String, String), Value>,
+}
+
+/// Server-sent events announcing changes to the documents of a model
+#[derive(Clone)]
+pub struct ChangeFeed {
+    state: Arc<Mutex<FeedState>>,
+}
+
+fn documents_of(model: &GxModel) -> BTreeMap<(String, String), Value> {
+    let (_, documents) = split_documents(model);
+    documents
+        .into_iter()
+        .map(|document| ((document.kind, document.id), document.doc))
+        .collect()
+}
+
+fn changes(
+    model_id: &str,
+    revision: &str,
+    old: &BTreeMap<(String, String), Value>,
+    new: &BTreeMap<(String, String), Value>,
+) -> Vec<ChangeEvent> {
+    let event = |key: &(String, String), action: &str| ChangeEvent {
+        model_id: model_id.to_owned(),
+        kind: key.0.clone(),
+        id: key.1.clone(),
+        action: action.to_owned(),
+        revision: revision.to_owned(),
+    };
+
+    let mut events = Vec::new();
+    for (key, doc) in new {
+        match old.get(key) {
+            None => events.push(event(key, "added")),
+            Some(old_doc) if old_doc != doc => events.push(event(key, "updated")),
+            Some(_) => {}
+        }
+    }
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            events.push(event(key, "removed"));
+        }
+    }
+    if events.is_empty() {
+        // Only the model header changed
+        events.push(event(&("model".to_owned(), model_id.to_owned()), "updated"));
+    }
+    events
+}
+
+fn to_sse(event: &ChangeEvent) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_default();
+    Bytes::from(format!("event: change\nid: {}\ndata: {}\n\n", event.revision, data))
+}
+
+/// Send data to the subscribers of a model, or to all of them, dropping the
+/// ones that have gone away
+fn broadcast(subscribers: &mut Vec<(String, UnboundedSender<Bytes>)>, model_id: Option<&str>, data: &Bytes) {
+    subscribers.retain(|&(ref id, ref subscriber)| {
+        if model_id.map_or(false, |model_id| model_id != id.as_str()) {
+            return true;
+        }
+        subscriber.unbounded_send(data.clone()).is_ok()
+    });
+}
+
+impl ChangeFeed {
+    pub fn new() -> ChangeFeed {
+        ChangeFeed {
+            state: Arc::new(Mutex::new(FeedState {
+                subscribers: Vec::new(),
+                revision: None,
+                documents: BTreeMap::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<FeedState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// A stream of server-sent events for every change to a model from now on
+    pub fn subscribe(&self, model_id: &str) -> UnboundedReceiver<Bytes> {
+        let (sender, receiver) = mpsc::unbounded();
+        let _ = sender.unbounded_send(Bytes::from_static(b"retry: 5000\n\n"));
+        self.lock().subscribers.push((model_id.to_owned(), sender));
+        receiver
+    }
+
+    /// Compare a model at a revision with the one seen last, and send an
+    /// event for every document that changed. The first model seen is only
+    /// remembered.
+    pub fn check(&self, model: &GxModel, revision: &str) {
+        let mut state = self.lock();
+        if state.revision.as_ref().map(|seen| seen.as_str()) == Some(revision) {
+            return;
+        }
+
+        let documents = documents_of(model);
+        let events = match state.revision {
+            Some(_) => changes(&model.id.to_string(), &revision, &state.documents, &documents),
+            None => Vec::new(),
+        };
+        state.revision = Some(revision.to_owned());
+        state.documents = documents;
+
+        for event in &events {
+            debug!("feed: {} {} '{}'", event.action, event.kind, event.id);
+            broadcast(&mut state.subscribers, Some(&event.model_id), &to_sse(event));
+        }
+    }
+
+    /// Send a comment to subscribers every `interval`, so that closed
+    /// connections are noticed and dropped
+    pub fn keepalive(&self, interval: Duration) {
+        let feed = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            broadcast(&mut feed.lock().subscribers, None, &Bytes::from_static(b": keepalive\n\n"));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+
+    fn documents(docs: &[(&str, &str, Value)]) -> BTreeMap<(String, String), Value> {
+        docs.iter()
+            .map(|&(kind, id, ref doc)| ((kind.to_owned(), id.to_owned()), doc.clone()))
+            .collect()
+    }
+
+    fn actions(events: &[ChangeEvent]) -> Vec<(&str, &str, &str)> {
+        events
+            .iter()
+            .map(|event| (event.action.as_str(), event.kind.as_str(), event.id.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn changed_documents_become_events() {
+        let old = documents(&[
+            ("xflows", "x1", json!({"name": "login"})),
+            ("xflows", "x2", json!({"name": "logout"})),
+        ]);
+        let new = documents(&[
+            ("xflows", "x1", json!({"name": "sign in"})),
+            ("pages", "p1", json!({"name": "home"})),
+        ]);
+        assert_eq!(
+            actions(&changes("m1", "r2", &old, &new)),
+            vec![("added", "pages", "p1"), ("updated", "xflows", "x1"), ("removed", "xflows", "x2")]
+        );
+        assert_eq!(actions(&changes("m1", "r2", &old, &old)), vec![("updated", "model", "m1")]);
+    }
+
+    #[test]
+    fn events_only_reach_subscribers_of_their_model() {
+        let feed = ChangeFeed::new();
+        let first = feed.subscribe("m1");
+        let second = feed.subscribe("m2");
+        broadcast(&mut feed.lock().subscribers, Some("m1"), &Bytes::from_static(b"change\n\n"));
+        drop(feed);
+
+        let received = |receiver: UnboundedReceiver<Bytes>| -> Vec<Bytes> {
+            receiver.wait().filter_map(|data| data.ok()).collect()
+        };
+        assert_eq!(received(first), vec![Bytes::from_static(b"retry: 5000\n\n"), Bytes::from_static(b"change\n\n")]);
+        assert_eq!(received(second), vec![Bytes::from_static(b"retry: 5000\n\n")]);
+    }
+}
@@ -0,0 +1,6 @@
+use gears::structure::gxmodel::GxModel;
+
+/// A model with a few entities, xflows, pages and a translation
+pub fn model() -> GxModel {
+    GxModel::from_json(include_str!("fixtures/model.json")).unwrap()
+}
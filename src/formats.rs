@@ -0,0 +1,261 @@
+use gears::structure::common::ModelLoadError;
+use gears::structure::gxmodel::GxModel;
+use rmp_serde;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use serde_yaml;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+use std::str;
+use toml;
+
+use app::Format;
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::JSON),
+            "yaml" | "yml" => Some(Format::YAML),
+            "toml" => Some(Format::TOML),
+            "msgpack" | "mp" => Some(Format::MSGPACK),
+            "ndjson" => Some(Format::NDJSON),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &str) -> Option<Format> {
+        Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| Format::from_name(&ext.to_lowercase()))
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Format> {
+        match mime.split(';').next().unwrap_or("").trim() {
+            "application/json" | "application/vnd.api+json" => Some(Format::JSON),
+            "application/x-yaml" | "application/yaml" | "text/yaml" => Some(Format::YAML),
+            "application/toml" => Some(Format::TOML),
+            "application/msgpack" | "application/x-msgpack" => Some(Format::MSGPACK),
+            "application/x-ndjson" => Some(Format::NDJSON),
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match *self {
+            Format::JSON => "application/json; charset=utf-8",
+            Format::YAML => "application/x-yaml; charset=utf-8",
+            Format::TOML => "application/toml; charset=utf-8",
+            Format::MSGPACK => "application/msgpack",
+            Format::NDJSON => "application/x-ndjson",
+        }
+    }
+
+    /// Guess the format of a serialized model from its first bytes
+    pub fn detect(data: &[u8]) -> Format {
+        match data.first() {
+            // fixmap, map16 and map32 markers
+            Some(&byte) if (byte >= 0x80 && byte <= 0x8f) || byte == 0xde || byte == 0xdf => {
+                return Format::MSGPACK
+            }
+            _ => {}
+        }
+
+        let text = str::from_utf8(data).unwrap_or("").trim_start();
+        if text.starts_with('{') {
+            return Format::JSON;
+        }
+
+        let first_line = text
+            .lines()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .unwrap_or("");
+        if first_line.starts_with('[') {
+            return Format::TOML;
+        }
+        match (first_line.find('='), first_line.find(':')) {
+            (Some(eq), Some(colon)) if eq < colon => Format::TOML,
+            (Some(_), None) => Format::TOML,
+            _ => Format::YAML,
+        }
+    }
+}
+
+fn format_error<E: ::std::fmt::Debug>(err: E) -> ModelLoadError {
+    ModelLoadError::InputError(format!("{:?}", err))
+}
+
+/// Parse a model, detecting the format from the content if none is given
+pub fn decode(data: &[u8], format: Option<&Format>) -> Result<GxModel, ModelLoadError> {
+    read_from(data, format)
+}
+
+pub fn encode(model: &GxModel, format: &Format) -> Result<Vec<u8>, ModelLoadError> {
+    let mut data = Vec::new();
+    write_to(model, format, &mut data)?;
+    Ok(data)
+}
+
+/// Deserialize a model straight from a reader, detecting the format from the
+/// first buffered bytes if none is given
+pub fn read_from<R: BufRead>(reader: R, format: Option<&Format>) -> Result<GxModel, ModelLoadError> {
+    read_serialized(reader, format)
+}
+
+/// Serialize a model straight into a writer. NDJSON writes the model header
+/// on the first line, followed by one line per document.
+pub fn write_to<W: Write>(model: &GxModel, format: &Format, writer: W) -> Result<(), ModelLoadError> {
+    match *format {
+        Format::NDJSON => write_ndjson(model, writer),
+        _ => write_serialized(model, format, writer),
+    }
+}
+
+fn write_line<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<(), ModelLoadError> {
+    serde_json::to_writer(&mut *writer, value).map_err(format_error)?;
+    writer.write_all(b"\n").map_err(format_error)
+}
+
+/// Write the model header, the model with empty document lists, and then each
+/// document in the order `bundle::split_documents` gives them. Documents are
+/// serialized one at a time, without building the model as a JSON value.
+fn write_ndjson<W: Write>(model: &GxModel, mut writer: W) -> Result<(), ModelLoadError> {
+    let header = json!({
+        "id": model.id,
+        "name": model.name,
+        "doctype": model.doctype,
+        "doctype_version": model.doctype_version,
+        "version": model.version,
+        "body": {"pages": [], "translations": [], "xflows": []},
+    });
+    write_line(&mut writer, &header)?;
+    write_line(&mut writer, &model.body.config)?;
+    write_line(&mut writer, &model.body.domain)?;
+    for page in &model.body.pages {
+        write_line(&mut writer, page)?;
+    }
+    for translation in &model.body.translations {
+        write_line(&mut writer, translation)?;
+    }
+    for xflow in &model.body.xflows {
+        write_line(&mut writer, xflow)?;
+    }
+    Ok(())
+}
+
+/// Deserialize any value from a reader. TOML has no streaming parser and is
+/// read into memory first.
+pub fn read_serialized<R, T>(mut reader: R, format: Option<&Format>) -> Result<T, ModelLoadError>
+where
+    R: BufRead,
+    T: DeserializeOwned,
+{
+    let format = match format {
+        Some(format) => format.clone(),
+        None => Format::detect(reader.fill_buf().map_err(format_error)?),
+    };
+    debug!("read_serialized: reading as {:?}", format);
+
+    match format {
+        Format::JSON => serde_json::from_reader(reader).map_err(format_error),
+        Format::YAML => serde_yaml::from_reader(reader).map_err(format_error),
+        Format::MSGPACK => rmp_serde::from_read(reader).map_err(format_error),
+        Format::TOML => {
+            let mut text = String::new();
+            reader.read_to_string(&mut text).map_err(format_error)?;
+            toml::from_str(&text).map_err(format_error)
+        }
+        Format::NDJSON => Err(ModelLoadError::InputError(
+            "NDJSON can only be used as an output format".to_owned(),
+        )),
+    }
+}
+
+/// Serialize any value into a writer, with NDJSON giving a single line of JSON
+pub fn write_serialized<W, T>(value: &T, format: &Format, mut writer: W) -> Result<(), ModelLoadError>
+where
+    W: Write,
+    T: Serialize,
+{
+    match *format {
+        Format::JSON => serde_json::to_writer_pretty(writer, value).map_err(format_error),
+        Format::YAML => serde_yaml::to_writer(writer, value).map_err(format_error),
+        Format::MSGPACK => rmp_serde::encode::write_named(&mut writer, value).map_err(format_error),
+        Format::TOML => {
+            // Going through toml::Value puts plain values before tables, which
+            // the TOML serializer requires
+            let value = toml::Value::try_from(value).map_err(format_error)?;
+            let text = toml::to_string_pretty(&value).map_err(format_error)?;
+            writer.write_all(text.as_bytes()).map_err(format_error)
+        }
+        Format::NDJSON => {
+            serde_json::to_writer(&mut writer, value).map_err(format_error)?;
+            writer.write_all(b"\n").map_err(format_error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bundle;
+    use diff::model_to_value;
+    use fixtures;
+
+    fn detected(data: &str) -> String {
+        format!("{:?}", Format::detect(data.as_bytes()))
+    }
+
+    #[test]
+    fn formats_are_detected_from_the_content() {
+        assert_eq!(detected("  {\"id\": 1}"), "JSON");
+        assert_eq!(detected("id: 1\nname: shop"), "YAML");
+        assert_eq!(detected("# model\nid = 1"), "TOML");
+        assert_eq!(detected("[body]\nid = 1"), "TOML");
+        assert_eq!(detected("url = \"http://example.com\""), "TOML");
+        assert_eq!(format!("{:?}", Format::detect(&[0x82, 0xa2])), "MSGPACK");
+    }
+
+    #[test]
+    fn formats_are_found_by_name_path_and_mime_type() {
+        assert_eq!(format!("{:?}", Format::from_name("yml")), "Some(YAML)");
+        assert_eq!(format!("{:?}", Format::from_path("model.MP")), "Some(MSGPACK)");
+        assert_eq!(format!("{:?}", Format::from_path("model")), "None");
+        assert_eq!(
+            format!("{:?}", Format::from_mime("application/vnd.api+json; charset=utf-8")),
+            "Some(JSON)"
+        );
+        assert_eq!(format!("{:?}", Format::from_mime("text/html")), "None");
+    }
+
+    #[test]
+    fn models_round_trip_through_every_format() {
+        let model = fixtures::model();
+        for format in &[Format::JSON, Format::YAML, Format::TOML, Format::MSGPACK] {
+            let data = encode(&model, format).unwrap();
+            let given = decode(&data, Some(format)).unwrap();
+            let detected = decode(&data, None).unwrap();
+            assert_eq!(model_to_value(&given), model_to_value(&model), "{:?}", format);
+            assert_eq!(model_to_value(&detected), model_to_value(&model), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn ndjson_has_the_header_and_documents_of_a_split_model() {
+        let model = fixtures::model();
+        let data = encode(&model, &Format::NDJSON).unwrap();
+        let lines: Vec<serde_json::Value> = str::from_utf8(&data)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        let (header, documents) = bundle::split_documents(&model);
+        let mut expected = vec![header];
+        expected.extend(documents.into_iter().map(|document| document.doc));
+        assert_eq!(lines, expected);
+        assert!(decode(&data, Some(&Format::NDJSON)).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use diff::{key_of, keyed, label_of};
+use serde_json::{Map, Value};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Strategy {
+    Replace,
+    Merge,
+    FailOnConflict,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "replace" => Some(Strategy::Replace),
+            "merge" => Some(Strategy::Merge),
+            "fail-on-conflict" => Some(Strategy::FailOnConflict),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Added,
+    Updated,
+    Unchanged,
+    Removed,
+    Conflict,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Action::Added => "added",
+            Action::Updated => "updated",
+            Action::Unchanged => "unchanged",
+            Action::Removed => "removed",
+            Action::Conflict => "conflict",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub action: Action,
+    pub kind: String,
+    pub label: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<9} {} '{}'", self.action.to_string(), self.kind, self.label)
+    }
+}
+
+#[derive(Debug)]
+pub struct ImportPlan {
+    pub model: Value,
+    pub entries: Vec<Entry>,
+}
+
+impl ImportPlan {
+    pub fn has_conflicts(&self) -> bool {
+        self.entries.iter().any(|entry| entry.action == Action::Conflict)
+    }
+
+    pub fn has_changes(&self) -> bool {
+        self.entries.iter().any(|entry| match entry.action {
+            Action::Added | Action::Updated | Action::Removed => true,
+            _ => false,
+        })
+    }
+}
+
+/// Compare the documents of an incoming model with those of an existing one by
+/// id, and work out the model to write for the given strategy. Document lists
+/// such as xflows and pages are compared per document, single documents such
+/// as the domain as a whole.
+pub fn plan(existing: Option<&Value>, incoming: &Value, strategy: &Strategy) -> ImportPlan {
+    let existing = match existing {
+        Some(existing) => existing,
+        None => {
+            return ImportPlan {
+                model: incoming.clone(),
+                entries: all_added(incoming),
+            }
+        }
+    };
+
+    let mut model = match *strategy {
+        Strategy::Replace => incoming.clone(),
+        _ => existing.clone(),
+    };
+    let mut entries = Vec::new();
+
+    let empty = Map::new();
+    let incoming_body = incoming
+        .get("body")
+        .and_then(|body| body.as_object())
+        .unwrap_or(&empty);
+
+    for (kind, incoming_docs) in incoming_body {
+        let pointer = format!("/body/{}", kind);
+
+        match *incoming_docs {
+            Value::Array(ref docs) => {
+                let existing_docs = keyed(existing, &pointer, "id");
+                for doc in docs {
+                    let id = key_of(doc, "id");
+                    let action = match existing_docs.get(&id) {
+                        None => Action::Added,
+                        Some(old) if *old == doc => Action::Unchanged,
+                        Some(_) if *strategy == Strategy::FailOnConflict => Action::Conflict,
+                        Some(_) => Action::Updated,
+                    };
+                    if *strategy == Strategy::Merge
+                        && (action == Action::Added || action == Action::Updated)
+                    {
+                        upsert(&mut model, &pointer, &id, doc);
+                    }
+                    if *strategy == Strategy::FailOnConflict && action == Action::Added {
+                        upsert(&mut model, &pointer, &id, doc);
+                    }
+                    push(&mut entries, action, kind, label_of(&id, doc));
+                }
+
+                if *strategy == Strategy::Replace {
+                    let incoming_docs = keyed(incoming, &pointer, "id");
+                    for (id, doc) in &existing_docs {
+                        if !incoming_docs.contains_key(id) {
+                            push(&mut entries, Action::Removed, kind, label_of(id, doc));
+                        }
+                    }
+                }
+            }
+            ref doc => {
+                let id = key_of(doc, "id");
+                let action = match existing.pointer(&pointer) {
+                    None => Action::Added,
+                    Some(old) if old == doc => Action::Unchanged,
+                    Some(_) if *strategy == Strategy::FailOnConflict => Action::Conflict,
+                    Some(_) => Action::Updated,
+                };
+                if (*strategy != Strategy::Replace && action == Action::Added)
+                    || (*strategy == Strategy::Merge && action == Action::Updated)
+                {
+                    if let Some(target) = model.pointer_mut("/body") {
+                        target[kind.as_str()] = doc.clone();
+                    }
+                }
+                push(&mut entries, action, kind, label_of(&id, doc));
+            }
+        }
+    }
+
+    ImportPlan {
+        model: model,
+        entries: entries,
+    }
+}
+
+fn all_added(incoming: &Value) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    if let Some(body) = incoming.get("body").and_then(|body| body.as_object()) {
+        for (kind, docs) in body {
+            match *docs {
+                Value::Array(ref docs) => {
+                    for doc in docs {
+                        push(&mut entries, Action::Added, kind, label_of(&key_of(doc, "id"), doc));
+                    }
+                }
+                ref doc => push(&mut entries, Action::Added, kind, label_of(&key_of(doc, "id"), doc)),
+            }
+        }
+    }
+    entries
+}
+
+fn upsert(model: &mut Value, pointer: &str, id: &str, doc: &Value) {
+    if let Some(docs) = model.pointer_mut(pointer).and_then(|docs| docs.as_array_mut()) {
+        match docs.iter().position(|existing| key_of(existing, "id") == id) {
+            Some(idx) => docs[idx] = doc.clone(),
+            None => docs.push(doc.clone()),
+        }
+    }
+}
+
+fn push(entries: &mut Vec<Entry>, action: Action, kind: &str, label: String) {
+    entries.push(Entry {
+        action: action,
+        kind: kind.to_owned(),
+        label: label,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(xflows: Value) -> Value {
+        json!({
+            "id": "model",
+            "body": {
+                "domain": {"id": "domain", "body": {"entities": []}},
+                "xflows": xflows
+            }
+        })
+    }
+
+    fn actions(plan: &ImportPlan) -> Vec<(Action, String)> {
+        plan.entries
+            .iter()
+            .map(|entry| (entry.action.clone(), entry.label.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn everything_is_added_without_an_existing_model() {
+        let incoming = model(json!([{"id": "a", "name": "login"}]));
+        let plan = plan(None, &incoming, &Strategy::Replace);
+
+        assert_eq!(plan.model, incoming);
+        assert!(plan.entries.iter().all(|entry| entry.action == Action::Added));
+        assert!(plan.has_changes());
+    }
+
+    #[test]
+    fn replace_takes_the_incoming_model_and_reports_removals() {
+        let existing = model(json!([{"id": "a", "v": 1}, {"id": "b", "v": 1}]));
+        let incoming = model(json!([{"id": "a", "v": 2}, {"id": "c", "v": 1}]));
+        let plan = plan(Some(&existing), &incoming, &Strategy::Replace);
+
+        assert_eq!(plan.model, incoming);
+        assert_eq!(
+            actions(&plan),
+            vec![
+                (Action::Unchanged, "domain".to_owned()),
+                (Action::Updated, "a".to_owned()),
+                (Action::Added, "c".to_owned()),
+                (Action::Removed, "b".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_existing_documents() {
+        let existing = model(json!([{"id": "a", "v": 1}, {"id": "b", "v": 1}]));
+        let incoming = model(json!([{"id": "a", "v": 2}, {"id": "c", "v": 1}]));
+        let plan = plan(Some(&existing), &incoming, &Strategy::Merge);
+
+        assert_eq!(
+            plan.model,
+            model(json!([{"id": "a", "v": 2}, {"id": "b", "v": 1}, {"id": "c", "v": 1}]))
+        );
+        assert!(!plan.has_conflicts());
+    }
+
+    #[test]
+    fn fail_on_conflict_reports_changed_documents() {
+        let existing = model(json!([{"id": "a", "v": 1}]));
+        let incoming = model(json!([{"id": "a", "v": 2}, {"id": "c", "v": 1}]));
+        let plan = plan(Some(&existing), &incoming, &Strategy::FailOnConflict);
+
+        assert!(plan.has_conflicts());
+        assert_eq!(
+            actions(&plan),
+            vec![
+                (Action::Unchanged, "domain".to_owned()),
+                (Action::Conflict, "a".to_owned()),
+                (Action::Added, "c".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unchanged_models_have_no_changes() {
+        let existing = model(json!([{"id": "a", "v": 1}]));
+        let plan = plan(Some(&existing), &existing.clone(), &Strategy::Merge);
+
+        assert!(!plan.has_changes());
+    }
+}
@@ -7,7 +7,9 @@ extern crate rustyline;
 extern crate actix;
 extern crate actix_web;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
+extern crate serde_yaml;
 #[macro_use]
 extern crate serde_derive;
 extern crate bytes;
@@ -16,63 +18,183 @@ extern crate uuid;
 
 extern crate actix_web_middleware_opa;
 extern crate jsonapi;
+extern crate json_patch;
+extern crate jsonwebtoken;
+extern crate notify;
+extern crate flate2;
+extern crate rmp_serde;
+extern crate sha2;
+extern crate tar;
+extern crate toml;
+extern crate zstd;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use gears::structure::common::{ModelLoadError, DocumentNature, DocumentFileSystemLoadable};
 use gears::structure::gxmodel::GxModel;
-use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::{self, Read};
+use std::io::{self, BufReader, BufWriter};
 use std::path::Path;
 
 extern crate env_logger;
 
+mod apierror;
 mod app;
-use app::{AppState, Format, ServerConfig};
+use app::{AppState, AuthConfig, CorsConfig, Format, ProjectConfig, ServerConfig};
+use merge::MergeOutcome;
+use modelstore::filesystem::FileSystemModelStore;
+use modelstore::model_executor::{ChangeInfo, ModelStore};
 
+mod auth;
+mod bundle;
+mod compress;
+mod diff;
+mod feed;
+#[cfg(test)]
+mod fixtures;
+mod formats;
+mod import;
+mod merge;
 mod modelstore;
+mod resource;
+mod select;
 mod server;
 mod shell;
+mod template;
+mod util;
+mod vcs;
+
+/// Read the project config file, in TOML. Without a config file the defaults
+/// are used.
+fn read_config(path: &str) -> Result<ProjectConfig, String> {
+    if !Path::new(path).exists() {
+        debug!("read_config: no config file at '{}'", path);
+        return Ok(ProjectConfig::default());
+    }
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| format!("{}", err))?;
+    toml::from_str(&contents).map_err(|err| format!("{}", err))
+}
+
+/// The server configuration from the config file, with the options given to
+/// `serve` on top
+fn server_config(config: &str, matches: &ArgMatches) -> Result<ServerConfig, String> {
+    let project_config =
+        read_config(config).map_err(|err| format!("Unable to read config file '{}': {}", config, err))?;
+
+    let mut server_config = project_config.server;
+    if let Some(url) = matches.value_of("opa-url") {
+        server_config.enable_opa = true;
+        server_config.opa_url = Some(url.to_string());
+    }
+    if let Some(token) = matches.value_of("admin-token") {
+        server_config.admin_token = Some(token.to_string());
+    }
+    if let Some(secret) = matches.value_of("jwt-secret") {
+        server_config.auth.get_or_insert_with(AuthConfig::default).secret = Some(secret.to_string());
+    }
+    if let Some(jwks) = matches.value_of("jwks") {
+        server_config.auth.get_or_insert_with(AuthConfig::default).jwks = Some(jwks.to_string());
+    }
+    if let Some(origins) = matches.values_of("cors-origin") {
+        server_config
+            .cors
+            .get_or_insert_with(CorsConfig::default)
+            .allowed_origins
+            .extend(origins.map(|origin| origin.to_string()));
+    }
+    debug!("Server Config : {:?}", server_config);
+    Ok(server_config)
+}
 
 fn load_model(path: &str) -> Result<GxModel, ModelLoadError> {
     GxModel::load_from_filesystem(path)
 }
 
-fn read_stdin() -> String {
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
+/// Open a possibly compressed file for reading, or stdin for `-`
+fn open_reader(path: &str) -> Result<Box<dyn BufRead>, ModelLoadError> {
+    if path == "-" {
+        Ok(Box::new(BufReader::new(io::stdin())))
+    } else {
+        compress::open(path).map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))
+    }
+}
+
+/// Load a model from a project directory, from an exported model file, which
+/// may be compressed, or for `-`, from stdin. Exported models are
+/// deserialized as they are read.
+fn read_model(path: &str, format: &Option<Format>) -> Result<GxModel, ModelLoadError> {
+    if Path::new(path).is_dir() {
+        load_model(path)
+    } else {
+        let format = format
+            .clone()
+            .or_else(|| Format::from_path(compress::strip_extension(path)));
+        formats::read_from(open_reader(path)?, format.as_ref())
+    }
+}
 
-    handle.read_to_string(&mut buffer).unwrap();
-    buffer
+/// Read the model given with `--input`, or from stdin
+fn read_input(appstate: &AppState) -> Result<GxModel, ModelLoadError> {
+    match appstate.input {
+        Some(ref path) => read_model(path, &appstate.format_in),
+        None => read_model("-", &appstate.format_in),
+    }
 }
 
-fn write_file(filename: &str, data: &str) -> () {
-    let path = Path::new(filename);
-    let display = path.display();
+/// Write a model to the file given with `--output`, or to stdout, serializing
+/// straight into the file or stream
+fn write_output(appstate: &AppState, model: &GxModel) -> Result<(), String> {
+    write_output_with(appstate, |writer| {
+        formats::write_to(model, &appstate.format_out, writer)
+    })
+}
 
-    let mut file = match File::create(&path) {
-        Err(why) => {
-            error!("couldn't create {}: {}", display, why.description());
-            panic!("couldn't create {}: {}", display, why.description());
+fn write_output_with<F>(appstate: &AppState, write: F) -> Result<(), String>
+where
+    F: Fn(&mut dyn Write) -> Result<(), ModelLoadError>,
+{
+    let format = &appstate.format_out;
+    let res = match appstate.output {
+        Some(ref path) => compress::write_with(path, appstate.backup, |writer| {
+            write(writer).map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))
+        })
+        .map_err(|err| format!("{:?}", err)),
+        None => {
+            let stdout = io::stdout();
+            let mut handle = BufWriter::new(stdout.lock());
+            write(&mut handle)
+                .map_err(|err| format!("{:?}", err))
+                .and_then(|_| {
+                    let newline = match *format {
+                        Format::JSON | Format::YAML | Format::TOML => handle.write_all(b"\n"),
+                        Format::MSGPACK | Format::NDJSON => Ok(()),
+                    };
+                    newline
+                        .and_then(|_| handle.flush())
+                        .map_err(|err| format!("{:?}", err))
+                })
         }
-        Ok(file) => file,
     };
 
-    match file.write_all(data.as_bytes()) {
-        Err(why) => {
-            error!("couldn't write to {}: {}", display, why.description());
-            panic!("couldn't write to {}: {}", display, why.description());
-        }
-        Ok(_) => debug!("successfully wrote to {}", display),
+    res.map_err(|err| format!("output error: {}", err))
+}
+
+fn load_value(path: &str) -> Result<serde_json::Value, ModelLoadError> {
+    if Path::new(path).is_dir() {
+        load_model(path).map(|model| diff::model_to_value(&model))
+    } else {
+        let mut buffer = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut buffer))
+            .map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))?;
+        serde_json::from_str(&buffer).map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))
     }
 }
 
-fn add_project_files(path: &str) -> () {
-    write_file(
-        &(format!("{}/.gitignore", path)),
-        r#"**/*tmp
+static PROJECT_GITIGNORE: &'static str = r#"**/*tmp
 **/*log
 **/*.bk
 **/*.swp
@@ -80,21 +202,19 @@ fn add_project_files(path: &str) -> () {
 history.gears-shell
 local.json
 out/
-"#,
-    );
+.history/
+"#;
 
-    write_file(
-        &(format!("{}/README.md", path)),
-        r#"
+static PROJECT_README: &'static str = r#"
 # gears project
 
 This project is suitable for version control.
 
-It is recommended to initialize this project with `git`, though any VCS will do.
+It is recommended to keep this project in `git`, though any VCS will do.
+`gears-cli init --git` creates the repository with an initial commit, and
+`gears-cli commit` only commits models that validate.
 
-    git init
-    git add .
-    git commit -m "Initial commit"
+    gears-cli commit -m "Add customer entity"
 
 ## Help
 
@@ -116,8 +236,38 @@ For general information, visit the project hub at
     << Running gears-shell
     >> sync
 
-"#,
-    );
+"#;
+
+/// Write the scaffold files of a project, leaving existing files alone unless
+/// `overwrite` is set
+fn add_project_files(path: &str, overwrite: bool, backup: bool) -> io::Result<()> {
+    let files = [
+        (".gitignore", PROJECT_GITIGNORE),
+        ("README.md", PROJECT_README),
+    ];
+
+    for &(name, data) in files.iter() {
+        let filename = format!("{}/{}", path, name);
+        if Path::new(&filename).exists() {
+            if overwrite {
+                util::write_file(&filename, data, backup)?;
+                println!("overwritten {}", filename);
+            } else {
+                println!("skipped     {}", filename);
+            }
+        } else {
+            util::write_file(&filename, data, backup)?;
+            println!("created     {}", filename);
+        }
+    }
+    Ok(())
+}
+
+fn is_empty_dir(path: &str) -> bool {
+    match fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => true,
+    }
 }
 
 fn main() {
@@ -132,7 +282,7 @@ fn main() {
                 .short("c")
                 .long("config")
                 .value_name("FILE")
-                .help("Sets a custom config file")
+                .help("Sets a custom config file, instead of project.conf in the project directory")
                 .takes_value(true),
         )
         .arg(
@@ -165,20 +315,39 @@ fn main() {
             Arg::with_name("input_format")
                 .long("input-format")
                 .value_name("input_format")
-                .possible_values(&["json", "yaml"])
-                .default_value("json")
-                .help("Sets the input format")
+                .possible_values(&["json", "yaml", "toml", "msgpack"])
+                .help("Sets the input format, detected from the input if not set")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("output_format")
                 .long("output-format")
                 .value_name("output_format")
-                .possible_values(&["json", "yaml"])
-                .default_value("json")
-                .help("Sets the output format")
+                .possible_values(&["json", "yaml", "toml", "msgpack", "ndjson"])
+                .help("Sets the output format, defaults to the --output extension or json")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .value_name("FILE")
+                .help("Read the model from a file instead of stdin, .gz and .zst are decompressed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Write the model to a file instead of stdout, .gz and .zst are compressed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backup")
+                .long("backup")
+                .help("Keep a .bk copy of files that are overwritten"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -186,12 +355,201 @@ fn main() {
                 .help("Sets the level of verbosity"),
         )
         .subcommand(SubCommand::with_name("shell").about("Run an interactive shell"))
-        .subcommand(SubCommand::with_name("init").about("Initialize a new project"))
-        .subcommand(SubCommand::with_name("export").about("Export an existing project"))
-        .subcommand(SubCommand::with_name("import").about("Import an existing project"))
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Initialize a new project")
+                .arg(
+                    Arg::with_name("git")
+                        .long("git")
+                        .help("Initialize a git repository and commit the new project"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .conflicts_with("merge")
+                        .help("Initialize even if the directory is not empty, replacing an existing model and its documents"),
+                )
+                .arg(
+                    Arg::with_name("merge")
+                        .long("merge")
+                        .help("Only add the model and scaffold files that are missing"),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .value_name("template")
+                        .help("Start from a template name, model directory or exported model file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .value_name("name")
+                        .help("Sets the project name used in templates, defaults to the directory name")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export an existing project")
+                .arg(
+                    Arg::with_name("bundle")
+                        .long("bundle")
+                        .value_name("FILE")
+                        .help("Write a single archive with a manifest and document checksums")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("only")
+                        .long("only")
+                        .value_name("kinds")
+                        .help("Only export documents of these kinds, such as xflows,pages")
+                        .use_delimiter(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .long("id")
+                        .value_name("uuid")
+                        .help("Only export the documents with these ids")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("select")
+                        .long("select")
+                        .value_name("selector")
+                        .help("Export the parts of the model matching a selector such as body.xflows[name=login]")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Import an existing project")
+                .arg(
+                    Arg::with_name("strategy")
+                        .long("strategy")
+                        .value_name("strategy")
+                        .possible_values(&["replace", "merge", "fail-on-conflict"])
+                        .default_value("replace")
+                        .help("Sets how documents that already exist in the project are handled")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Show what would change without writing anything"),
+                )
+                .arg(
+                    Arg::with_name("bundle")
+                        .long("bundle")
+                        .value_name("FILE")
+                        .help("Read a bundle written by export --bundle, verifying its checksums")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("partial")
+                        .long("partial")
+                        .conflicts_with("bundle")
+                        .help("Apply a fragment written by export --only, --id or --select, replacing the documents it holds"),
+                )
+                .arg(
+                    Arg::with_name("other-model")
+                        .long("other-model")
+                        .requires("partial")
+                        .help("Apply a fragment exported from another model"),
+                ),
+        )
         .subcommand(SubCommand::with_name("transform").about("Transform an existing project"))
         .subcommand(SubCommand::with_name("validate").about("Validate an existing project"))
         .subcommand(SubCommand::with_name("build").about("Build project artifacts"))
+        .subcommand(
+            SubCommand::with_name("commit")
+                .about("Validate the project and commit it to git")
+                .arg(
+                    Arg::with_name("message")
+                        .short("m")
+                        .long("message")
+                        .value_name("message")
+                        .help("Sets the commit message")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Show semantic differences between two projects")
+                .arg(
+                    Arg::with_name("left")
+                        .help("Path of the original project")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("right")
+                        .help("Path of the changed project or export, or - to read an export from stdin")
+                        .default_value("-")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the differences as JSON"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Three-way merge of projects or model documents, usable as a git merge driver")
+                .arg(
+                    Arg::with_name("base")
+                        .help("Path of the common ancestor")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("ours")
+                        .help("Path of our version, overwritten with the merge result")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("theirs")
+                        .help("Path of their version")
+                        .required(true)
+                        .index(3),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List the saved revisions of the model, or show or restore one")
+                .arg(
+                    Arg::with_name("show")
+                        .long("show")
+                        .value_name("revision")
+                        .help("Write the model as it was at a revision")
+                        .conflicts_with("restore")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("restore")
+                        .long("restore")
+                        .value_name("revision")
+                        .help("Restore the model to a revision, saving it as a new revision")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("message")
+                        .short("m")
+                        .long("message")
+                        .value_name("message")
+                        .help("Sets the message of the restored revision")
+                        .requires("restore")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("serve")
                 .about("Run a HTTP REST API for project")
@@ -200,191 +558,502 @@ fn main() {
                         .long("opa-url")
                         .help("Sets the OPA url")
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("admin-token")
+                        .long("admin-token")
+                        .env("GEARS_ADMIN_TOKEN")
+                        .help("Bearer token of privileged callers, who may force updates of invalid models")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jwt-secret")
+                        .long("jwt-secret")
+                        .env("GEARS_JWT_SECRET")
+                        .help("Requires HS256 bearer tokens signed with this secret")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("jwks")
+                        .long("jwks")
+                        .value_name("FILE")
+                        .help("Requires RS256 bearer tokens signed with a key from this JWKS file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cors-origin")
+                        .long("cors-origin")
+                        .value_name("origin")
+                        .help("Allows cross-origin requests from this origin, or * for any")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
                 ),
         )
         .get_matches();
 
-    let config = matches.value_of("config").unwrap_or("project.conf");
     let path = matches.value_of("path").unwrap_or(".");
+    let config = matches
+        .value_of("config")
+        .map_or_else(|| Path::new(path).join("project.conf").to_string_lossy().into_owned(), |config| config.to_owned());
     let output_path = matches.value_of("output_path").unwrap_or(".");
 
-    let input_format = if matches.value_of("input_format").unwrap_or("json") == "yaml" {
-        Format::YAML
-    } else {
-        Format::JSON
-    };
+    let input_format = matches
+        .value_of("input_format")
+        .and_then(Format::from_name);
 
-    let output_format = if matches.value_of("output_format").unwrap_or("json") == "yaml" {
-        Format::YAML
-    } else {
-        Format::JSON
-    };
+    let input = matches.value_of("input");
+    let output = matches.value_of("output");
 
-    let locale = matches.value_of("locale").unwrap_or("en_US");
+    let output_format = matches
+        .value_of("output_format")
+        .and_then(Format::from_name)
+        .or_else(|| output.and_then(|path| Format::from_path(compress::strip_extension(path))))
+        .unwrap_or(Format::JSON);
 
-    let server_config = if let Some(matches) = matches.subcommand_matches("serve") {
-        match matches.value_of("opa-url") {
-            Some(url) => ServerConfig {
-                enable_opa: true,
-                opa_url: Some(url.to_string()),
-            },
-            None => ServerConfig {
-                enable_opa: false,
-                opa_url: None,
-            },
-        }
-    } else {
-        ServerConfig {
-            enable_opa: false,
-            opa_url: None,
-        }
-    };
+    let locale = matches.value_of("locale").unwrap_or("en_US");
 
     let mut appstate = AppState {
         locale: locale.to_string(),
-        path_config: config.to_string(),
+        path_config: config,
         path_in: path.to_string(),
         path_out: output_path.to_string(),
         format_in: input_format.clone(),
         format_out: output_format.clone(),
+        input: input.map(|path| path.to_string()),
+        output: output.map(|path| path.to_string()),
+        backup: matches.is_present("backup"),
     };
 
-    debug!("App Config : {:?}", server_config);
-    debug!("Server Config : {:?}", appstate);
+    debug!("App Config : {:?}", appstate);
 
-    match matches.subcommand_name() {
-        Some("init") => subcommand_init(&appstate),
+    let res = match matches.subcommand_name() {
+        Some("init") => subcommand_init(&appstate, matches.subcommand_matches("init").unwrap()),
         Some("shell") => subcommand_shell(&appstate),
-        Some("export") => subcommand_export(&mut appstate),
-        Some("import") => subcommand_import(&mut appstate),
+        Some("export") => subcommand_export(&mut appstate, matches.subcommand_matches("export").unwrap()),
+        Some("import") => subcommand_import(&mut appstate, matches.subcommand_matches("import").unwrap()),
         Some("transform") => subcommand_transform(&appstate),
         Some("validate") => subcommand_validate(&appstate),
         Some("build") => subcommand_build(&appstate),
-        Some("serve") => subcommand_serve(&appstate, &server_config),
-        None => println!("No subcommand was used"),
-        _ => println!("Some other subcommand was used"),
+        Some("commit") => subcommand_commit(&appstate, matches.subcommand_matches("commit").unwrap()),
+        Some("diff") => subcommand_diff(&appstate, matches.subcommand_matches("diff").unwrap()),
+        // Like git merge drivers, exit with 1 on conflicts and 2 on errors
+        Some("merge") => match subcommand_merge(matches.subcommand_matches("merge").unwrap()) {
+            Ok(MergeOutcome::Clean) => Ok(()),
+            Ok(MergeOutcome::Conflict) => ::std::process::exit(1),
+            Err(err) => {
+                error!("{}", err);
+                ::std::process::exit(2);
+            }
+        },
+        Some("history") => subcommand_history(&appstate, matches.subcommand_matches("history").unwrap()),
+        Some("serve") => server_config(&appstate.path_config, matches.subcommand_matches("serve").unwrap())
+            .and_then(|config| subcommand_serve(&appstate, &config)),
+        None => {
+            println!("No subcommand was used");
+            Ok(())
+        }
+        _ => {
+            println!("Some other subcommand was used");
+            Ok(())
+        }
+    };
+
+    if let Err(err) = res {
+        error!("{}", err);
+        ::std::process::exit(1);
     }
 }
 
-fn subcommand_init(appstate: &AppState) -> () {
+fn subcommand_init(appstate: &AppState, matches: &ArgMatches) -> Result<(), String> {
     info!("init: in directory {}", appstate.path_in);
-    let _ = gears::util::fs::init_new_model_dir(&appstate.path_in);
-    add_project_files(&appstate.path_in);
-}
 
-fn subcommand_shell(appstate: &AppState) -> () {
-    info!("shell: in directory {}", appstate.path_in);
-    match load_model(&appstate.path_in) {
-        Ok(mut model) => {
-            shell::shell(&mut model, &appstate);
+    let force = matches.is_present("force");
+    let merge = matches.is_present("merge");
+    let has_model = load_model(&appstate.path_in).is_ok();
+
+    if !force && !merge && !is_empty_dir(&appstate.path_in) {
+        if has_model {
+            println!("'{}' already contains a model", appstate.path_in);
+        } else {
+            println!("'{}' is not empty", appstate.path_in);
+        }
+        return Err("Use --merge to add missing files, or --force to overwrite".to_owned());
+    }
+
+    let path = &appstate.path_in;
+    let write = |dir: &str| init_model(dir, appstate, matches);
+    if merge && has_model {
+        println!("skipped     model in '{}'", path);
+    } else if merge && !is_empty_dir(path) {
+        // Only add the files of the model that are missing, leaving any
+        // other files alone
+        let (created, skipped) = util::add_missing_files(path, write).map_err(|err| format!("init error: {:?}", err))?;
+        for file in created {
+            println!("created     {}", file.display());
         }
-        Err(err) => {
-            error!("subcommand_shell : {:?}", err);
+        for file in skipped {
+            println!("skipped     {}", file.display());
         }
+    } else if force {
+        // Replacing prunes the documents of a previous model, which would
+        // otherwise be loaded into the new one
+        util::replace_dir(path, appstate.backup, write).map_err(|err| format!("init error: {:?}", err))?;
+        println!("created     model in '{}'", path);
+    } else {
+        util::write_dir(path, appstate.backup, write).map_err(|err| format!("init error: {:?}", err))?;
+        println!("created     model in '{}'", path);
     }
-}
+    add_project_files(&appstate.path_in, force, appstate.backup)
+        .map_err(|err| format!("init error: {:?}", err))?;
 
-fn subcommand_validate(appstate: &AppState) -> () {
-    info!("validate: model in '{}'", appstate.path_in);
-    if let Ok(model) = load_model(&appstate.path_in) {
-        let path_sep = ";".to_owned();
-        let errors = gears::validation::common::validate_gxmodel(&model);
-
-        if errors.len() > 0 {
-            for error in &errors {
-                println!(
-                    "Error '{}' - Path '{}'",
-                    error.message,
-                    error.paths.join(&path_sep)
-                );
-            }
+    if matches.is_present("git") {
+        if vcs::init(&appstate.path_in).map_err(|err| format!("init error: {}", err))? {
+            println!("Initialized git repository in '{}'", appstate.path_in);
         } else {
-            println!("Model '{}' validates OK", model.id);
+            println!("'{}' is already in a git repository, not committing", appstate.path_in);
         }
-    } else {
-        error!("subcommand_validate : ERROR");
     }
+    Ok(())
 }
 
-fn subcommand_transform(appstate: &AppState) -> () {
-    let buffer = read_stdin();
+/// Write a new model into `path`, from the template if one is given
+fn init_model(path: &str, appstate: &AppState, matches: &ArgMatches) -> Result<(), String> {
+    match matches.value_of("template") {
+        Some(template) => {
+            let name = match matches.value_of("name") {
+                Some(name) => name.to_owned(),
+                None => project_name(&appstate.path_in),
+            };
+            let model = template::load(template, &name, &appstate.locale)
+                .map_err(|err| format!("{:?}", err))?;
+            model
+                .write_to_filesystem(path)
+                .map(|_| ())
+                .map_err(|err| format!("{:?}", err))
+        }
+        None => gears::util::fs::init_new_model_dir(path)
+            .map(|_| ())
+            .map_err(|err| format!("{:?}", err)),
+    }
+}
 
-    let model = match appstate.format_in {
-        Format::YAML => match gears::structure::gxmodel::GxModel::from_yaml(&buffer) {
-            Ok(model) => model,
-            Err(err) => {
-                error!("transform error: {:?}", err);
-                return ();
-            }
-        },
-        Format::JSON => match gears::structure::gxmodel::GxModel::from_json(&buffer) {
-            Ok(model) => model,
-            Err(err) => {
-                error!("transform error: {:?}", err);
-                return ();
-            }
-        },
-    };
+fn subcommand_shell(appstate: &AppState) -> Result<(), String> {
+    info!("shell: in directory {}", appstate.path_in);
+    let mut model = load_model(&appstate.path_in).map_err(|err| format!("shell error: {:?}", err))?;
+    shell::shell(&mut model, &appstate);
+    Ok(())
+}
 
-    match appstate.format_out {
-        Format::YAML => println!("{}", model.to_yaml()),
-        Format::JSON => println!("{}", model.to_json()),
+fn validate_model(model: &GxModel) -> bool {
+    let path_sep = ";".to_owned();
+    let errors = gears::validation::common::validate_gxmodel(&model);
+
+    for error in &errors {
+        println!(
+            "Error '{}' - Path '{}'",
+            error.message,
+            error.paths.join(&path_sep)
+        );
     }
+    errors.len() == 0
 }
 
-fn subcommand_build(appstate: &AppState) -> () {
+fn subcommand_validate(appstate: &AppState) -> Result<(), String> {
+    info!("validate: model in '{}'", appstate.path_in);
+    let model = load_model(&appstate.path_in).map_err(|err| format!("validate error: {:?}", err))?;
+    if validate_model(&model) {
+        println!("Model '{}' validates OK", model.id);
+        Ok(())
+    } else {
+        Err(format!("Model '{}' has errors", model.id))
+    }
+}
+
+fn subcommand_commit(appstate: &AppState, matches: &ArgMatches) -> Result<(), String> {
+    info!("commit: model in '{}'", appstate.path_in);
+    let message = matches.value_of("message").unwrap_or("");
+
+    let model = load_model(&appstate.path_in).map_err(|err| format!("commit error: {:?}", err))?;
+    if !validate_model(&model) {
+        return Err(format!("Model '{}' has errors, not committing", model.id));
+    }
+    vcs::commit(&appstate.path_in, message).map_err(|err| format!("commit error: {}", err))?;
+    println!("Committed model '{}'", model.id);
+    Ok(())
+}
+
+fn subcommand_transform(appstate: &AppState) -> Result<(), String> {
+    let model = read_input(&appstate).map_err(|err| format!("transform error: {:?}", err))?;
+    write_output(&appstate, &model)
+}
+
+fn subcommand_build(appstate: &AppState) -> Result<(), String> {
     info!(
         "build: model in '{}', building assets in '{}'",
         appstate.path_in, appstate.path_out
     );
 
-    if let Ok(mut model) = load_model(&appstate.path_in) {
-        // model.pad_all_translations();
-        // let model_locale = model.as_locale(&appstate.locale).unwrap();
+    let model = load_model(&appstate.path_in).map_err(|err| format!("build error: {:?}", err))?;
+    // model.pad_all_translations();
+    // let model_locale = model.as_locale(&appstate.locale).unwrap();
+
+    util::write_dir(&appstate.path_out, appstate.backup, |path| {
+        gears::util::fs::build_to_react_app(&model, path)
+    })
+    .map_err(|err| format!("build error: {:?}", err))
+}
+
+fn subcommand_diff(appstate: &AppState, matches: &ArgMatches) -> Result<(), String> {
+    let load = |path: &str| read_model(path, &appstate.format_in);
+
+    let left_path = matches.value_of("left").unwrap_or(".");
+    let right_path = matches.value_of("right").unwrap_or("-");
+    info!("diff: model in '{}' against '{}'", left_path, right_path);
+
+    let (left, right) = match (load(left_path), load(right_path)) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(err), _) | (_, Err(err)) => return Err(format!("diff error: {:?}", err)),
+    };
+
+    let model_diff = diff::diff_models(&left, &right);
 
-        let _ = gears::util::fs::build_to_react_app(&model, &appstate.path_out);
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&model_diff).unwrap());
+    } else if model_diff.is_empty() {
+        println!("No differences");
     } else {
-        error!("subcommand_build : ERROR");
+        for change in &model_diff.changes {
+            println!("{}", change);
+        }
     }
+    Ok(())
 }
 
-fn subcommand_serve(appstate: &AppState, config: &ServerConfig) -> () {
+/// Merge `ours` and `theirs` against `base` into `ours`, which is left alone
+/// when it is a model directory and the merge has conflicts
+fn subcommand_merge(matches: &ArgMatches) -> Result<MergeOutcome, String> {
+    let base_path = matches.value_of("base").unwrap_or("");
+    let ours_path = matches.value_of("ours").unwrap_or("");
+    let theirs_path = matches.value_of("theirs").unwrap_or("");
+    info!(
+        "merge: '{}' and '{}' with base '{}'",
+        ours_path, theirs_path, base_path
+    );
+
+    let base = load_value(base_path).map_err(|err| format!("merge error: {:?}", err))?;
+    let ours = load_value(ours_path).map_err(|err| format!("merge error: {:?}", err))?;
+    let theirs = load_value(theirs_path).map_err(|err| format!("merge error: {:?}", err))?;
+
+    let result = merge::merge_values(&base, &ours, &theirs);
+    for conflict in &result.conflicts {
+        println!("CONFLICT '{}'", conflict);
+    }
+
+    if Path::new(ours_path).is_dir() {
+        // A model directory can only hold a valid model, so conflicts are
+        // reported and the directory is left untouched
+        if !result.is_clean() {
+            return Ok(MergeOutcome::Conflict);
+        }
+        let model = GxModel::from_json(&result.merged.to_string()).map_err(|err| format!("merge error: {:?}", err))?;
+        util::write_dir(ours_path, false, |path| model.write_to_filesystem(path))
+            .map_err(|err| format!("merge error: {:?}", err))?;
+    } else {
+        let data = serde_json::to_string_pretty(&result.merged).unwrap();
+        util::write_file(ours_path, &data, false).map_err(|err| format!("merge error: {:?}", err))?;
+    }
+    Ok(result.outcome())
+}
+
+/// The full id of the saved revision starting with `prefix`
+fn resolve_revision(store: &FileSystemModelStore, prefix: &str) -> Result<String, String> {
+    let revisions = store.revisions("").map_err(|err| format!("{:?}", err))?;
+    let mut matching: Vec<String> = revisions
+        .into_iter()
+        .map(|info| info.revision)
+        .filter(|revision| revision.starts_with(prefix))
+        .collect();
+    matching.dedup();
+    match matching.len() {
+        0 => Err(format!("No revision '{}'", prefix)),
+        1 => Ok(matching.remove(0)),
+        _ => Err(format!("Revision '{}' is ambiguous", prefix)),
+    }
+}
+
+fn subcommand_history(appstate: &AppState, matches: &ArgMatches) -> Result<(), String> {
+    info!("history: model in '{}'", appstate.path_in);
+    let store = FileSystemModelStore::new(&appstate.path_in).map_err(|err| format!("history error: {:?}", err))?;
+
+    if let Some(prefix) = matches.value_of("show").or(matches.value_of("restore")) {
+        let model = resolve_revision(&store, prefix)
+            .and_then(|revision| store.get_revision("", &revision).map_err(|err| format!("{:?}", err)))
+            .map_err(|err| format!("history error: {}", err))?;
+
+        if matches.is_present("show") {
+            return write_output(&appstate, &model);
+        }
+
+        let change = ChangeInfo {
+            author: ::std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+            message: matches
+                .value_of("message")
+                .map_or(format!("Restored revision {}", prefix), |msg| msg.to_owned()),
+        };
+        store
+            .update_at(&model.to_json(), None, &change)
+            .map_err(|err| format!("history error: {:?}", err))?;
+        println!("Restored revision '{}'", prefix);
+        return Ok(());
+    }
+
+    let revisions = store.revisions("").map_err(|err| format!("history error: {:?}", err))?;
+    if revisions.is_empty() {
+        println!("No revisions saved yet");
+    }
+    for info in revisions.iter().rev() {
+        println!(
+            "{}  {}  {:<20} {}",
+            &info.revision[..::std::cmp::min(12, info.revision.len())],
+            info.timestamp,
+            info.author,
+            info.message
+        );
+    }
+    Ok(())
+}
+
+fn subcommand_serve(appstate: &AppState, config: &ServerConfig) -> Result<(), String> {
     info!("serve: model in '{}'", appstate.path_in);
 
     // let model = load_model(&appstate.path_in);
 
-    server::serve(&appstate.path_in, &config);
+    server::serve(&appstate.path_in, &config).map_err(|err| format!("serve error: {}", err))
 }
 
-fn subcommand_import(appstate: &mut AppState) -> () {
-    let buffer = read_stdin();
+fn subcommand_import(appstate: &mut AppState, matches: &ArgMatches) -> Result<(), String> {
+    let strategy = import::Strategy::from_name(matches.value_of("strategy").unwrap_or("replace"))
+        .unwrap_or(import::Strategy::Replace);
 
-    let model = match appstate.format_in {
-        Format::YAML => match gears::structure::gxmodel::GxModel::from_yaml(&buffer) {
-            Ok(model) => model,
-            Err(err) => {
-                error!("import error: {:?}", err);
-                return ();
-            }
-        },
-        Format::JSON => match gears::structure::gxmodel::GxModel::from_json(&buffer) {
-            Ok(model) => model,
-            Err(err) => {
-                error!("import error: {:?}", err);
-                return ();
-            }
-        },
+    if matches.is_present("partial") {
+        // Fragments are always applied item by item, replacing what is there
+        if matches.occurrences_of("strategy") > 0 {
+            return Err("import error: --strategy cannot be used with --partial".to_owned());
+        }
+        return import_partial(&appstate, matches.is_present("dry-run"), matches.is_present("other-model"));
+    }
+
+    let model = match matches.value_of("bundle") {
+        Some(path) => bundle::read(path),
+        None => read_input(&appstate),
+    };
+    let model = model.map_err(|err| format!("import error: {:?}", err))?;
+
+    let existing = load_model(&appstate.path_in)
+        .ok()
+        .map(|existing| diff::model_to_value(&existing));
+    let plan = import::plan(existing.as_ref(), &diff::model_to_value(&model), &strategy);
+
+    for entry in &plan.entries {
+        println!("{}", entry);
+    }
+
+    if plan.has_conflicts() {
+        return Err("Conflicting documents found, nothing imported".to_owned());
+    }
+    if matches.is_present("dry-run") {
+        println!("Dry run, nothing imported");
+        return Ok(());
+    }
+    if !plan.has_changes() {
+        println!("No changes to import");
+        return Ok(());
+    }
+
+    let model = GxModel::from_json(&plan.model.to_string()).map_err(|err| format!("import error: {:?}", err))?;
+
+    // Replacing drops the documents that are not in the imported model
+    let write = |path: &str| model.write_to_filesystem(path);
+    let res = match strategy {
+        import::Strategy::Replace => util::replace_dir(&appstate.path_in, appstate.backup, write),
+        _ => util::write_dir(&appstate.path_in, appstate.backup, write),
     };
+    res.map_err(|err| format!("import error: {:?}", err))
+}
+
+fn import_partial(appstate: &AppState, dry_run: bool, other_model: bool) -> Result<(), String> {
+    let path = appstate.input.as_ref().map_or("-", |path| path.as_str());
+    let format = appstate
+        .format_in
+        .clone()
+        .or_else(|| Format::from_path(compress::strip_extension(path)));
+
+    let fragment: select::Fragment = open_reader(path)
+        .and_then(|reader| formats::read_serialized(reader, format.as_ref()))
+        .map_err(|err| format!("import error: {:?}", err))?;
 
-    let _ = &model.write_to_filesystem( &appstate.path_in)
-    .unwrap();
+    let mut doc = load_model(&appstate.path_in)
+        .map(|model| diff::model_to_value(&model))
+        .map_err(|err| format!("import error: {:?}", err))?;
+
+    if doc.get("id").and_then(|id| id.as_str()) != Some(fragment.model_id.as_str()) {
+        if !other_model {
+            return Err(format!(
+                "import error: the fragment is from model '{}', use --other-model to apply it anyway",
+                fragment.model_id
+            ));
+        }
+        println!("Fragment is from model '{}', applying anyway", fragment.model_id);
+    }
+
+    let report = select::apply(&mut doc, &fragment).map_err(|err| format!("import error: {}", err))?;
+    for line in &report {
+        println!("{}", line);
+    }
+
+    if dry_run {
+        println!("Dry run, nothing imported");
+        return Ok(());
+    }
+
+    let model = GxModel::from_json(&doc.to_string()).map_err(|err| format!("import error: {:?}", err))?;
+
+    util::write_dir(&appstate.path_in, appstate.backup, |path| {
+        model.write_to_filesystem(path)
+    })
+    .map_err(|err| format!("import error: {:?}", err))
 }
 
-fn subcommand_export(appstate: &mut AppState) -> () {
-    let model = GxModel::load_from_filesystem(&appstate.path_in).unwrap();
+fn subcommand_export(appstate: &mut AppState, matches: &ArgMatches) -> Result<(), String> {
+    let model = load_model(&appstate.path_in).map_err(|err| format!("export error: {:?}", err))?;
+
+    if let Some(path) = matches.value_of("bundle") {
+        return bundle::write(&model, path, appstate.backup).map_err(|err| format!("export error: {:?}", err));
+    }
+
+    let kinds: Vec<&str> = matches.values_of("only").map_or(Vec::new(), |kinds| kinds.collect());
+    let ids: Vec<&str> = matches.values_of("id").map_or(Vec::new(), |ids| ids.collect());
+    let selectors: Vec<&str> = matches.values_of("select").map_or(Vec::new(), |exprs| exprs.collect());
+
+    if kinds.is_empty() && ids.is_empty() && selectors.is_empty() {
+        return write_output(&appstate, &model);
+    }
 
-    match appstate.format_out {
-        Format::YAML => println!("{}", model.to_yaml()),
-        Format::JSON => println!("{}", model.to_json()),
+    let doc = diff::model_to_value(&model);
+    let mut items = Vec::new();
+    if !kinds.is_empty() || !ids.is_empty() {
+        items.extend(select::select_documents(&doc, &kinds, &ids));
     }
+    for expr in selectors {
+        items.extend(select::select_path(&doc, expr).map_err(|err| format!("export error: {}", err))?);
+    }
+
+    let fragment = select::Fragment {
+        model_id: model.id.to_string(),
+        items: items,
+    };
+    write_output_with(&appstate, |writer| {
+        formats::write_serialized(&fragment, &appstate.format_out, writer)
+    })
 }
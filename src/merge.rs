@@ -0,0 +1,250 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+
+pub static MARKER_OURS: &'static str = "<<<<<<< ours";
+pub static MARKER_BASE: &'static str = "||||||| base";
+pub static MARKER_THEIRS: &'static str = ">>>>>>> theirs";
+
+/// Whether a merge left conflicts to resolve
+#[derive(Debug, PartialEq)]
+pub enum MergeOutcome {
+    Clean,
+    Conflict,
+}
+
+#[derive(Debug)]
+pub struct MergeResult {
+    pub merged: Value,
+    pub conflicts: Vec<String>,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    pub fn outcome(&self) -> MergeOutcome {
+        if self.is_clean() {
+            MergeOutcome::Clean
+        } else {
+            MergeOutcome::Conflict
+        }
+    }
+}
+
+/// Three-way merge of two documents against their common ancestor. Objects are
+/// merged field by field and arrays of documents are merged by `id`, or by
+/// `name` for documents without one such as entities. Fields
+/// changed differently on both sides are replaced by an object holding the
+/// `ours`, `base` and `theirs` values under conflict marker keys.
+pub fn merge_values(base: &Value, ours: &Value, theirs: &Value) -> MergeResult {
+    let mut conflicts = Vec::new();
+    let merged = merge_at("", Some(base), Some(ours), Some(theirs), &mut conflicts);
+    MergeResult {
+        merged: merged.unwrap_or(Value::Null),
+        conflicts: conflicts,
+    }
+}
+
+fn merge_at(
+    path: &str,
+    base: Option<&Value>,
+    ours: Option<&Value>,
+    theirs: Option<&Value>,
+    conflicts: &mut Vec<String>,
+) -> Option<Value> {
+    if ours == theirs {
+        return ours.cloned();
+    }
+    if base == ours {
+        return theirs.cloned();
+    }
+    if base == theirs {
+        return ours.cloned();
+    }
+
+    match (ours, theirs) {
+        (Some(&Value::Object(ref ours)), Some(&Value::Object(ref theirs))) => {
+            let base = match base {
+                Some(&Value::Object(ref base)) => Some(base),
+                _ => None,
+            };
+            Some(merge_objects(path, base, ours, theirs, conflicts))
+        }
+        (Some(&Value::Array(ref ours)), Some(&Value::Array(ref theirs))) => match key_field(ours, theirs) {
+            Some(key) => {
+                let base = match base {
+                    Some(&Value::Array(ref base)) if has_key(base, key) => Some(base),
+                    _ => None,
+                };
+                Some(merge_documents(path, key, base, ours, theirs, conflicts))
+            }
+            None => {
+                conflicts.push(path.to_owned());
+                Some(conflict_marker(base, Some(&Value::Array(ours.clone())), Some(&Value::Array(theirs.clone()))))
+            }
+        },
+        _ => {
+            conflicts.push(path.to_owned());
+            Some(conflict_marker(base, ours, theirs))
+        }
+    }
+}
+
+fn merge_objects(
+    path: &str,
+    base: Option<&Map<String, Value>>,
+    ours: &Map<String, Value>,
+    theirs: &Map<String, Value>,
+    conflicts: &mut Vec<String>,
+) -> Value {
+    let mut keys: BTreeSet<&String> = ours.keys().chain(theirs.keys()).collect();
+    if let Some(base) = base {
+        keys.extend(base.keys());
+    }
+
+    let mut merged = Map::new();
+    for key in keys {
+        let field = merge_at(
+            &format!("{}/{}", path, key),
+            base.and_then(|base| base.get(key)),
+            ours.get(key),
+            theirs.get(key),
+            conflicts,
+        );
+        if let Some(val) = field {
+            merged.insert(key.clone(), val);
+        }
+    }
+    Value::Object(merged)
+}
+
+fn merge_documents(
+    path: &str,
+    key: &str,
+    base: Option<&Vec<Value>>,
+    ours: &Vec<Value>,
+    theirs: &Vec<Value>,
+    conflicts: &mut Vec<String>,
+) -> Value {
+    let find = |docs: Option<&Vec<Value>>, id: &str| -> Option<Value> {
+        docs.and_then(|docs| docs.iter().find(|doc| doc_id(doc, key) == id).cloned())
+    };
+
+    // Keep the order of our side, with documents only present on their side
+    // appended in their order
+    let mut ids: Vec<String> = ours.iter().map(|doc| doc_id(doc, key)).collect();
+    for doc in theirs {
+        let id = doc_id(doc, key);
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    if let Some(base) = base {
+        for doc in base {
+            let id = doc_id(doc, key);
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+
+    let mut merged = Vec::new();
+    for id in ids {
+        let doc = merge_at(
+            &format!("{}/{}", path, id),
+            find(base, &id).as_ref(),
+            find(Some(ours), &id).as_ref(),
+            find(Some(theirs), &id).as_ref(),
+            conflicts,
+        );
+        if let Some(doc) = doc {
+            merged.push(doc);
+        }
+    }
+    Value::Array(merged)
+}
+
+/// Fields identifying the documents in an array, in order of preference
+static KEY_FIELDS: &'static [&'static str] = &["id", "name"];
+
+/// The field identifying the documents on both sides, if there is one
+fn key_field(ours: &Vec<Value>, theirs: &Vec<Value>) -> Option<&'static str> {
+    KEY_FIELDS
+        .iter()
+        .cloned()
+        .find(|key| has_key(ours, key) && has_key(theirs, key))
+}
+
+fn has_key(docs: &Vec<Value>, key: &str) -> bool {
+    docs.iter().all(|doc| doc.get(key).is_some())
+}
+
+fn doc_id(doc: &Value, key: &str) -> String {
+    match doc.get(key) {
+        Some(&Value::String(ref id)) => id.clone(),
+        Some(id) => id.to_string(),
+        None => doc.to_string(),
+    }
+}
+
+fn conflict_marker(base: Option<&Value>, ours: Option<&Value>, theirs: Option<&Value>) -> Value {
+    let mut marker = Map::new();
+    marker.insert(MARKER_OURS.to_owned(), ours.cloned().unwrap_or(Value::Null));
+    marker.insert(MARKER_BASE.to_owned(), base.cloned().unwrap_or(Value::Null));
+    marker.insert(MARKER_THEIRS.to_owned(), theirs.cloned().unwrap_or(Value::Null));
+    Value::Object(marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(name: &str, attributes: &[&str]) -> Value {
+        let attributes: Vec<Value> = attributes
+            .iter()
+            .map(|attr| json!({"name": attr, "vtype": "string"}))
+            .collect();
+        json!({"name": name, "attributes": attributes})
+    }
+
+    #[test]
+    fn entities_are_merged_by_name() {
+        let base = json!({"entities": [entity("item", &["name"]), entity("order", &[])]});
+        let ours = json!({"entities": [entity("item", &["name", "price"]), entity("order", &[])]});
+        let theirs = json!({"entities": [entity("item", &["name"]), entity("order", &["total"]), entity("customer", &[])]});
+
+        let result = merge_values(&base, &ours, &theirs);
+        assert!(result.is_clean(), "{:?}", result.conflicts);
+        assert_eq!(
+            result.merged,
+            json!({"entities": [entity("item", &["name", "price"]), entity("order", &["total"]), entity("customer", &[])]})
+        );
+    }
+
+    #[test]
+    fn documents_are_merged_by_id() {
+        let base = json!({"xflows": [{"id": "a", "name": "one"}, {"id": "b", "name": "two"}]});
+        let ours = json!({"xflows": [{"id": "a", "name": "uno"}, {"id": "b", "name": "two"}]});
+        let theirs = json!({"xflows": [{"id": "a", "name": "one"}]});
+
+        let result = merge_values(&base, &ours, &theirs);
+        assert_eq!(result.outcome(), MergeOutcome::Clean);
+        assert_eq!(result.merged, json!({"xflows": [{"id": "a", "name": "uno"}]}));
+    }
+
+    #[test]
+    fn conflicting_changes_are_marked() {
+        let base = json!({"entities": [{"name": "item", "label": "Item"}]});
+        let ours = json!({"entities": [{"name": "item", "label": "Article"}]});
+        let theirs = json!({"entities": [{"name": "item", "label": "Product"}]});
+
+        let result = merge_values(&base, &ours, &theirs);
+        assert_eq!(result.outcome(), MergeOutcome::Conflict);
+        assert_eq!(result.conflicts, vec!["/entities/item/label".to_owned()]);
+        assert_eq!(
+            result.merged["entities"][0]["label"],
+            json!({MARKER_OURS: "Article", MARKER_BASE: "Item", MARKER_THEIRS: "Product"})
+        );
+    }
+}
@@ -3,13 +3,149 @@ use futures::Future;
 use gears;
 use gears::structure::common::{ModelLoadError, DocumentNature, DocumentFileSystemLoadable};
 use gears::structure::gxmodel::GxModel;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::model_executor::{
+    check_revision, revision_of, validate, ChangeInfo, ModelPatch, ModelStore, ModelStoreCreate,
+    ModelStoreDelete, ModelStoreGet, ModelStoreGetRevision, ModelStoreList, ModelStoreNew,
+    ModelStorePatch, ModelStoreRevisions, ModelStoreUpdate, RevisionInfo, StoreError, StoredModel,
+};
+use util;
+
+/// Delay for collecting file system events into one, so that a git checkout
+/// reloads the model once
+static WATCH_DELAY_MS: u64 = 500;
+
+/// Whether a changed file is not part of the model, such as the history,
+/// git's own files or a model being written to a staging directory
+fn is_ignored(path: &Path) -> bool {
+    path.components().any(|part| {
+        let part = part.as_os_str();
+        part == OsStr::new(HISTORY_DIR)
+            || part == OsStr::new(".git")
+            || part.to_string_lossy().starts_with(".staging.")
+    })
+}
 
+/// The modification time and size of the files under a directory, by their
+/// canonical path, leaving out the files that are not part of the model
+type FileTimes = HashMap<PathBuf, (SystemTime, u64)>;
+
+fn file_times(dir: &Path, times: &mut FileTimes) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if is_ignored(&path) {
+            continue;
+        }
+        match entry.metadata() {
+            Ok(ref meta) if meta.is_dir() => file_times(&path, times),
+            Ok(ref meta) => {
+                if let (Ok(path), Ok(modified)) = (fs::canonicalize(&path), meta.modified()) {
+                    times.insert(path, (modified, meta.len()));
+                }
+            }
+            Err(_) => {}
+        }
+    }
+}
 
-use super::model_executor::{ModelStore};
+/// Whether a file is as it was when the times were taken
+fn is_unchanged(times: &FileTimes, path: &Path) -> bool {
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    match (times.get(&path), fs::metadata(&path)) {
+        (Some(&(modified, len)), Ok(meta)) => meta.modified().ok() == Some(modified) && meta.len() == len,
+        _ => false,
+    }
+}
+
+/// Directory in the model directory holding a snapshot of every saved
+/// revision, and an index of them
+static HISTORY_DIR: &'static str = ".history";
+static HISTORY_INDEX: &'static str = "index.json";
+static HISTORY_LOCK: &'static str = "lock";
+
+/// How long to wait for another process to release the history lock
+static LOCK_WAIT_MS: u64 = 10_000;
+static LOCK_RETRY_MS: u64 = 50;
+
+static NO_DELETE: &'static str = "Models cannot be deleted from a filesystem store";
+
+fn history_error<E: ::std::fmt::Debug>(err: E) -> ModelLoadError {
+    ModelLoadError::InputError(format!("{:?}", err))
+}
+
+/// Lock file in the history directory, held while the model and its history
+/// are written, so that `history --restore` and a running server do not
+/// overwrite each other's index
+struct HistoryLock {
+    path: PathBuf,
+}
+
+impl HistoryLock {
+    fn acquire(dir: &Path, wait: Duration) -> Result<Self, ModelLoadError> {
+        fs::create_dir_all(dir).map_err(history_error)?;
+        let path = dir.join(HISTORY_LOCK);
+        let start = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let _ = write!(file, "{}", process::id());
+                    return Ok(HistoryLock { path });
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && start.elapsed() < wait => {
+                    thread::sleep(Duration::from_millis(LOCK_RETRY_MS));
+                }
+                Err(err) => {
+                    return Err(ModelLoadError::InputError(format!(
+                        "Unable to lock '{}', remove it if no other gears-cli is running : {:?}",
+                        path.display(),
+                        err
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for HistoryLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
 
 #[derive(Clone)]
 pub struct FileSystemModelStore {
     root: String,
+    /// Held while checking the revision of the model and writing it, shared
+    /// by the clones of the store in each server worker
+    lock: Arc<Mutex<()>>,
+    /// The model as last loaded and its revision, until the files under the
+    /// root change
+    cache: Arc<RwLock<Option<StoredModel>>>,
+    /// The files as the store last wrote them, so that the watcher can tell
+    /// the store's own writes from other changes
+    written: Arc<Mutex<FileTimes>>,
+    /// Called when the model changes, by the watcher for changes on disk and
+    /// by the store itself after it writes the model
+    changed: Arc<Mutex<Option<Arc<Fn() + Send + Sync>>>>,
 }
 
 impl FileSystemModelStore {
@@ -17,28 +153,186 @@ impl FileSystemModelStore {
         match GxModel::load_from_filesystem(&path) {
             Ok(_) => Ok(FileSystemModelStore {
                 root: path.to_owned(),
+                lock: Arc::new(Mutex::new(())),
+                cache: Arc::new(RwLock::new(None)),
+                written: Arc::new(Mutex::new(HashMap::new())),
+                changed: Arc::new(Mutex::new(None)),
             }),
             Err(err) => Err(ModelLoadError::BadStructure("Unable to init".to_owned())),
         }
     }
+
+    /// Load the model from disk and cache it with its revision
+    fn reload(&self) -> Result<StoredModel, ModelLoadError> {
+        let model = GxModel::load_from_filesystem(&self.root)?;
+        let stored = StoredModel {
+            revision: revision_of(&model),
+            model: Arc::new(model),
+        };
+        *self.cache.write().unwrap_or_else(|err| err.into_inner()) = Some(stored.clone());
+        Ok(stored)
+    }
+
+    /// The model and its revision, loaded again only when the files have
+    /// changed
+    pub fn cached(&self) -> Result<StoredModel, ModelLoadError> {
+        if let Some(ref stored) = *self.cache.read().unwrap_or_else(|err| err.into_inner()) {
+            return Ok(stored.clone());
+        }
+        self.reload()
+    }
+
+    /// Write a model to disk, replacing the documents of the current one,
+    /// remembering the files as written, and cache it
+    fn write(&self, model: &GxModel) -> Result<StoredModel, ModelLoadError> {
+        util::replace_dir(&self.root, false, |dir| model.write_to_filesystem(dir))
+            .map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))?;
+        let mut times = HashMap::new();
+        file_times(Path::new(&self.root), &mut times);
+        *self.written.lock().unwrap_or_else(|err| err.into_inner()) = times;
+        let stored = self.reload()?;
+        self.notify();
+        Ok(stored)
+    }
+
+    fn notify(&self) {
+        let changed = self.changed.lock().unwrap_or_else(|err| err.into_inner()).clone();
+        if let Some(changed) = changed {
+            changed();
+        }
+    }
+
+    /// Whether a changed file was last written by the store itself
+    fn is_own_write(&self, path: &Path) -> bool {
+        is_unchanged(&self.written.lock().unwrap_or_else(|err| err.into_inner()), path)
+    }
+
+    pub fn invalidate(&self) {
+        *self.cache.write().unwrap_or_else(|err| err.into_inner()) = None;
+    }
+
+    /// Watch the files under the root, dropping the cached model when any of
+    /// them change and then calling `changed`. The store and its clones also
+    /// call `changed` after writing the model themselves.
+    pub fn watch<F>(&self, changed: F) -> Result<(), String>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let changed: Arc<Fn() + Send + Sync> = Arc::new(changed);
+        *self.changed.lock().unwrap_or_else(|err| err.into_inner()) = Some(changed.clone());
+
+        let (sender, receiver) = channel();
+        let mut watcher = watcher(sender, Duration::from_millis(WATCH_DELAY_MS)).map_err(|err| format!("{:?}", err))?;
+        watcher
+            .watch(&self.root, RecursiveMode::Recursive)
+            .map_err(|err| format!("{:?}", err))?;
+
+        let store = self.clone();
+        thread::spawn(move || {
+            // The watcher stops when dropped, so it lives as long as the thread
+            let _watcher = watcher;
+            for event in receiver {
+                let path = match event {
+                    DebouncedEvent::Create(path)
+                    | DebouncedEvent::Write(path)
+                    | DebouncedEvent::Remove(path)
+                    | DebouncedEvent::Rename(_, path) => Some(path),
+                    DebouncedEvent::Rescan => None,
+                    DebouncedEvent::Error(err, path) => {
+                        error!("watch: {:?} {:?}", err, path);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if path.map_or(false, |path| is_ignored(&path) || store.is_own_write(&path)) {
+                    continue;
+                }
+                debug!("watch: files changed in {}", store.root);
+                store.invalidate();
+                changed();
+            }
+        });
+        Ok(())
+    }
+
+    fn history_path(&self) -> PathBuf {
+        Path::new(&self.root).join(HISTORY_DIR)
+    }
+
+    /// Lock the model for writing, against the other server workers and
+    /// against other processes
+    fn lock_for_write(&self) -> Result<(MutexGuard<()>, HistoryLock), ModelLoadError> {
+        let guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let lock = HistoryLock::acquire(&self.history_path(), Duration::from_millis(LOCK_WAIT_MS))?;
+        Ok((guard, lock))
+    }
+
+    fn read_history(&self) -> Result<Vec<RevisionInfo>, ModelLoadError> {
+        let path = self.history_path().join(HISTORY_INDEX);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let file = File::open(path).map_err(history_error)?;
+        serde_json::from_reader(BufReader::new(file)).map_err(history_error)
+    }
+
+    /// Save a snapshot of a model and add it to the history
+    fn record(&self, stored: &StoredModel, change: &ChangeInfo) -> Result<RevisionInfo, ModelLoadError> {
+        let dir = self.history_path();
+        fs::create_dir_all(&dir).map_err(history_error)?;
+
+        let snapshot = dir.join(format!("{}.json", stored.revision));
+        if !snapshot.exists() {
+            util::write_file(&snapshot.to_string_lossy(), stored.model.to_json(), false).map_err(history_error)?;
+        }
+
+        let mut history = self.read_history()?;
+        let info = RevisionInfo {
+            revision: stored.revision.clone(),
+            author: change.author.clone(),
+            timestamp: util::timestamp(),
+            message: change.message.clone(),
+        };
+        history.push(info.clone());
+        let index = serde_json::to_vec_pretty(&history).map_err(history_error)?;
+        util::write_file(&dir.join(HISTORY_INDEX).to_string_lossy(), index, false).map_err(history_error)?;
+        Ok(info)
+    }
+
+    /// Write a model over the current one, keeping both in the history. The
+    /// model as it was before the history was kept is saved first.
+    fn save(&self, current: &StoredModel, model: &GxModel, change: &ChangeInfo) -> Result<StoredModel, ModelLoadError> {
+        if self.read_history()?.is_empty() {
+            self.record(
+                current,
+                &ChangeInfo {
+                    author: "unknown".to_owned(),
+                    message: "Model before its history was kept".to_owned(),
+                },
+            )?;
+        }
+        let stored = self.write(model)?;
+        self.record(&stored, change)?;
+        Ok(stored)
+    }
 }
 
 impl ModelStore for FileSystemModelStore {
-    fn list(&self) -> Result<Vec<GxModel>, ModelLoadError> {
-        match GxModel::load_from_filesystem(&self.root) {
-            Ok(res) => Ok(vec![res]),
+    fn list(&self) -> Result<Vec<Arc<GxModel>>, ModelLoadError> {
+        match self.cached() {
+            Ok(res) => Ok(vec![res.model]),
             Err(err) => Err(err)
         }
     }
 
-    fn get(&self, _id: &str) -> Result<GxModel, ModelLoadError> {
-        GxModel::load_from_filesystem(&self.root)
+    fn get(&self, _id: &str) -> Result<Arc<GxModel>, ModelLoadError> {
+        self.cached().map(|stored| stored.model)
     }
 
-    fn new(&self) -> Result<GxModel, ModelLoadError> {
+    fn new(&self) -> Result<Arc<GxModel>, ModelLoadError> {
         info!("init: in directory {}", self.root);
         match gears::util::fs::init_new_model_dir(&self.root) {
-            Ok(_) => GxModel::load_from_filesystem(&self.root),
+            Ok(_) => self.reload().map(|stored| stored.model),
             Err(err) => {
                 let msg = format!("{:?}", err);
                 Err(ModelLoadError::InputError(msg))
@@ -46,46 +340,83 @@ impl ModelStore for FileSystemModelStore {
         }
     }
 
-    fn create(&self, json: &str) -> Result<GxModel, ModelLoadError> {
+    fn create(&self, json: &str) -> Result<Arc<GxModel>, ModelLoadError> {
         info!("create: in directory {}", self.root);
         match GxModel::from_json(&json) {
-            Ok(model) => {
-                match &model.write_to_filesystem(&self.root) {
-                    Ok(_) => GxModel::load_from_filesystem(&self.root),
-                    Err(msg) => {
-                        let msg = format!("{:?}", msg);
-                        Err(ModelLoadError::InputError(msg))
-                    }
-                }
-            },
-            Err(err) =>{
+            Ok(model) => self.write(&model).map(|stored| stored.model),
+            Err(err) => {
                 let msg = format!("{:?}", err);
                 Err(ModelLoadError::InputError(msg))
             }
         }
     }
 
-    fn update(&self, json: &str) -> Result<GxModel, ModelLoadError> {
+    fn update(&self, json: &str) -> Result<Arc<GxModel>, ModelLoadError> {
         info!("update: in directory {}", self.root);
         match GxModel::from_json(&json) {
-            Ok(model) => {
-                match &model.write_to_filesystem(&self.root) {
-                    Ok(_) => GxModel::load_from_filesystem(&self.root),
-                    Err(msg) => {
-                        let msg = format!("{:?}", msg);
-                        Err(ModelLoadError::InputError(msg))
-                    }
-                }
-            },
-            Err(err) =>{
+            Ok(model) => self.write(&model).map(|stored| stored.model),
+            Err(err) => {
                 let msg = format!("{:?}", err);
                 Err(ModelLoadError::InputError(msg))
             }
         }
     }
 
-    fn delete(&self, json: &str) -> Result<(), ModelLoadError> {
-        unimplemented!()
+    fn delete(&self, _id: &str) -> Result<(), ModelLoadError> {
+        Err(ModelLoadError::InputError(NO_DELETE.to_owned()))
+    }
+
+    fn revision(&self, _id: &str) -> Result<String, ModelLoadError> {
+        self.cached().map(|stored| stored.revision)
+    }
+
+    fn update_at(&self, json: &str, expected: Option<&[String]>, change: &ChangeInfo) -> Result<StoredModel, StoreError> {
+        info!("update: in directory {}", self.root);
+        let model = GxModel::from_json(&json)
+            .map_err(|err| StoreError::Input(ModelLoadError::InputError(format!("{:?}", err))))?;
+        let _lock = self.lock_for_write().map_err(StoreError::Load)?;
+        let current = self.cached().map_err(StoreError::Load)?;
+        check_revision(&current.revision, expected)?;
+        self.save(&current, &model, change).map_err(StoreError::Load)
+    }
+
+    fn patch(
+        &self,
+        id: &str,
+        patch: &ModelPatch,
+        expected: Option<&[String]>,
+        force: bool,
+        change: &ChangeInfo,
+    ) -> Result<StoredModel, StoreError> {
+        info!("patch: in directory {}", self.root);
+        let _lock = self.lock_for_write().map_err(StoreError::Load)?;
+        let current = self.cached().map_err(StoreError::Load)?;
+        check_revision(&current.revision, expected)?;
+
+        let mut doc: Value = serde_json::from_str(&current.model.to_json())
+            .map_err(|err| StoreError::Load(ModelLoadError::InputError(format!("{:?}", err))))?;
+        patch.apply(&mut doc).map_err(StoreError::Patch)?;
+        let model = GxModel::from_json(&doc.to_string())
+            .map_err(|err| StoreError::Input(ModelLoadError::InputError(format!("{:?}", err))))?;
+
+        let errors = validate(&model);
+        if !errors.is_empty() && !force {
+            return Err(StoreError::Invalid(errors));
+        }
+        self.save(&current, &model, change).map_err(StoreError::Load)
+    }
+
+    fn revisions(&self, _id: &str) -> Result<Vec<RevisionInfo>, ModelLoadError> {
+        self.read_history()
+    }
+
+    fn get_revision(&self, _id: &str, revision: &str) -> Result<GxModel, ModelLoadError> {
+        if !self.read_history()?.iter().any(|info| info.revision == revision) {
+            return Err(ModelLoadError::InputError(format!("No revision '{}'", revision)));
+        }
+        let snapshot = self.history_path().join(format!("{}.json", revision));
+        let json = fs::read_to_string(snapshot).map_err(history_error)?;
+        GxModel::from_json(&json).map_err(history_error)
     }
 }
 
@@ -93,86 +424,227 @@ impl Actor for FileSystemModelStore {
     type Context = SyncContext<Self>;
 
     fn started(&mut self, ctx: &mut SyncContext<Self>) {
-        println!("Actor is alive");
+        debug!("Actor is alive");
     }
 
     fn stopped(&mut self, ctx: &mut SyncContext<Self>) {
-        println!("Actor is stopped");
+        debug!("Actor is stopped");
     }
 }
 
-struct ModelStoreList;
-
-impl Message for ModelStoreList {
-    type Result = Result<Vec<GxModel>, ModelLoadError>;
-}
-
 impl Handler<ModelStoreList> for FileSystemModelStore {
-    type Result = Result<Vec<GxModel>, ModelLoadError>;
+    type Result = Result<Vec<Arc<GxModel>>, ModelLoadError>;
 
     fn handle(&mut self, msg: ModelStoreList, ctx: &mut SyncContext<Self>) -> Self::Result {
         self.list()
     }
 }
 
-struct ModelStoreGet<'a> {
-    id: &'a str,
-}
+impl Handler<ModelStoreGet> for FileSystemModelStore {
+    type Result = Result<StoredModel, ModelLoadError>;
 
-impl<'a> Message for ModelStoreGet<'a> {
-    type Result = Result<GxModel, ModelLoadError>;
+    fn handle(&mut self, msg: ModelStoreGet, ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.cached()
+    }
 }
 
-impl<'a> Handler<ModelStoreGet<'a>> for FileSystemModelStore {
-    type Result = Result<GxModel, ModelLoadError>;
+impl Handler<ModelStoreNew> for FileSystemModelStore {
+    type Result = Result<Arc<GxModel>, ModelLoadError>;
 
-    fn handle(&mut self, msg: ModelStoreGet, ctx: &mut SyncContext<Self>) -> Self::Result {
-        self.get(&msg.id)
+    fn handle(&mut self, msg: ModelStoreNew, ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.new()
     }
 }
 
-struct ModelStoreNew;
+impl Handler<ModelStoreCreate> for FileSystemModelStore {
+    type Result = Result<Arc<GxModel>, ModelLoadError>;
 
-impl Message for ModelStoreNew {
-    type Result = Result<GxModel, ModelLoadError>;
+    fn handle(&mut self, msg: ModelStoreCreate, ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.create(&msg.json)
+    }
 }
 
-impl Handler<ModelStoreNew> for FileSystemModelStore {
-    type Result = Result<GxModel, ModelLoadError>;
+impl Handler<ModelStoreUpdate> for FileSystemModelStore {
+    type Result = Result<StoredModel, StoreError>;
 
-    fn handle(&mut self, msg: ModelStoreNew, ctx: &mut SyncContext<Self>) -> Self::Result {
-        self.new()
+    fn handle(&mut self, msg: ModelStoreUpdate, ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.update_at(&msg.json, msg.expected.as_ref().map(|tags| tags.as_slice()), &msg.change)
     }
 }
 
-struct ModelStoreCreate<'a> {
-    json: &'a str,
+impl Handler<ModelStorePatch> for FileSystemModelStore {
+    type Result = Result<StoredModel, StoreError>;
+
+    fn handle(&mut self, msg: ModelStorePatch, ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.patch(
+            &msg.id,
+            &msg.patch,
+            msg.expected.as_ref().map(|tags| tags.as_slice()),
+            msg.force,
+            &msg.change,
+        )
+    }
 }
 
-impl<'a> Message for ModelStoreCreate<'a> {
-    type Result = Result<GxModel, ModelLoadError>;
+impl Handler<ModelStoreDelete> for FileSystemModelStore {
+    type Result = Result<(), StoreError>;
+
+    fn handle(&mut self, msg: ModelStoreDelete, ctx: &mut SyncContext<Self>) -> Self::Result {
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let current = self.revision(&msg.id).map_err(StoreError::Load)?;
+        check_revision(&current, msg.expected.as_ref().map(|tags| tags.as_slice()))?;
+        Err(StoreError::Unsupported(NO_DELETE.to_owned()))
+    }
 }
 
-impl<'a> Handler<ModelStoreCreate<'a>> for FileSystemModelStore {
-    type Result = Result<GxModel, ModelLoadError>;
+impl Handler<ModelStoreRevisions> for FileSystemModelStore {
+    type Result = Result<Vec<RevisionInfo>, ModelLoadError>;
 
-    fn handle(&mut self, msg: ModelStoreCreate, ctx: &mut SyncContext<Self>) -> Self::Result {
-        self.create(msg.json)
+    fn handle(&mut self, msg: ModelStoreRevisions, ctx: &mut SyncContext<Self>) -> Self::Result {
+        self.revisions(&msg.id)
     }
 }
 
-struct ModelStoreDelete<'a> {
-    id: &'a str,
+impl Handler<ModelStoreGetRevision> for FileSystemModelStore {
+    type Result = Result<(RevisionInfo, GxModel), StoreError>;
+
+    fn handle(&mut self, msg: ModelStoreGetRevision, ctx: &mut SyncContext<Self>) -> Self::Result {
+        let info = self
+            .revisions(&msg.id)
+            .map_err(StoreError::Load)?
+            .into_iter()
+            .find(|info| info.revision == msg.revision)
+            .ok_or_else(|| StoreError::NotFound(format!("No revision '{}'", msg.revision)))?;
+        let model = self.get_revision(&msg.id, &msg.revision).map_err(StoreError::Load)?;
+        Ok((info, model))
+    }
 }
 
-impl<'a> Message for ModelStoreDelete<'a> {
-    type Result = Result<(), ModelLoadError>;
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::model_to_value;
+    use fixtures;
+    use std::env;
+
+    /// A store holding the fixture model in a new directory
+    fn fixture_store(name: &str) -> (PathBuf, FileSystemModelStore) {
+        let dir = env::temp_dir().join(format!("gears-cli-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fixtures::model().write_to_filesystem(&dir.to_string_lossy()).unwrap();
+        let store = FileSystemModelStore::new(&dir.to_string_lossy()).unwrap();
+        (dir, store)
+    }
 
-impl<'a> Handler<ModelStoreDelete<'a>> for FileSystemModelStore {
-    type Result = Result<(), ModelLoadError>;
+    fn xflow_ids(model: &GxModel) -> Vec<String> {
+        let mut ids: Vec<String> = model_to_value(model)["body"]["xflows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|xflow| xflow["id"].as_str().unwrap().to_owned())
+            .collect();
+        ids.sort();
+        ids
+    }
 
-    fn handle(&mut self, msg: ModelStoreDelete, ctx: &mut SyncContext<Self>) -> Self::Result {
-        self.delete(msg.id)
+    #[test]
+    fn history_and_git_files_are_ignored() {
+        assert!(is_ignored(Path::new("/project/.history/index.json")));
+        assert!(is_ignored(Path::new("/project/.git/HEAD")));
+        assert!(is_ignored(Path::new("/project/.staging.42-1.tmp/xflows/login.json")));
+        assert!(!is_ignored(Path::new("/project/domain/domain.json")));
+    }
+
+    #[test]
+    fn the_history_lock_is_exclusive() {
+        let dir = env::temp_dir().join(format!("gears-cli-lock-{}", process::id()));
+        {
+            let _lock = HistoryLock::acquire(&dir, Duration::from_millis(0)).unwrap();
+            assert!(dir.join(HISTORY_LOCK).exists());
+            assert!(HistoryLock::acquire(&dir, Duration::from_millis(100)).is_err());
+        }
+        assert!(!dir.join(HISTORY_LOCK).exists());
+        assert!(HistoryLock::acquire(&dir, Duration::from_millis(0)).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_changed_since_they_were_written_are_noticed() {
+        let dir = env::temp_dir().join(format!("gears-cli-times-{}", process::id()));
+        fs::create_dir_all(dir.join(HISTORY_DIR)).unwrap();
+        fs::write(dir.join("model.json"), "{}").unwrap();
+        fs::write(dir.join(HISTORY_DIR).join(HISTORY_INDEX), "[]").unwrap();
+
+        let mut times = HashMap::new();
+        file_times(&dir, &mut times);
+        assert_eq!(times.len(), 1);
+        assert!(is_unchanged(&times, &dir.join("model.json")));
+
+        fs::write(dir.join("model.json"), "{\"changed\": true}").unwrap();
+        assert!(!is_unchanged(&times, &dir.join("model.json")));
+        assert!(!is_unchanged(&times, &dir.join("other.json")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn patches_can_remove_related_documents() {
+        let (dir, store) = fixture_store("patch");
+        let change = ChangeInfo {
+            author: "test".to_owned(),
+            message: "Removed an xflow".to_owned(),
+        };
+        let patch = ModelPatch::Json(serde_json::from_value(json!([{"op": "remove", "path": "/body/xflows/0"}])).unwrap());
+        let patched = store.patch("", &patch, None, true, &change).unwrap();
+        assert_eq!(xflow_ids(&patched.model).len(), 1);
+
+        let loaded = GxModel::load_from_filesystem(&dir.to_string_lossy()).unwrap();
+        assert_eq!(xflow_ids(&loaded), xflow_ids(&patched.model));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restoring_a_revision_removes_later_documents() {
+        let (dir, store) = fixture_store("restore");
+        let change = |message: &str| ChangeInfo {
+            author: "test".to_owned(),
+            message: message.to_owned(),
+        };
+        let original = store.cached().unwrap();
+
+        let mut doc = model_to_value(&original.model);
+        let mut xflow = doc["body"]["xflows"][0].clone();
+        xflow["id"] = json!("8d3c5a5e-5b1f-4c7e-9a53-0a6f3a1d2b99");
+        xflow["name"] = json!("logout");
+        doc["body"]["xflows"].as_array_mut().unwrap().push(xflow);
+        let added = store.update_at(&doc.to_string(), None, &change("Added an xflow")).unwrap();
+        assert_eq!(xflow_ids(&added.model).len(), 3);
+
+        let model = store.get_revision("", &original.revision).unwrap();
+        let restored = store.update_at(&model.to_json(), None, &change("Restored")).unwrap();
+        assert_eq!(restored.revision, original.revision);
+
+        let loaded = GxModel::load_from_filesystem(&dir.to_string_lossy()).unwrap();
+        assert_eq!(xflow_ids(&loaded), xflow_ids(&original.model));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_are_announced_by_the_writing_thread() {
+        let (dir, store) = fixture_store("announce");
+        let announced = Arc::new(Mutex::new(Vec::new()));
+        let watched = store.clone();
+        let seen = announced.clone();
+        store
+            .watch(move || seen.lock().unwrap().push(watched.cached().unwrap().revision))
+            .unwrap();
+
+        let model = fixtures::model();
+        let stored = store.update_at(&model.to_json(), None, &ChangeInfo {
+            author: "test".to_owned(),
+            message: "Saved".to_owned(),
+        });
+        assert!(announced.lock().unwrap().contains(&stored.unwrap().revision));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
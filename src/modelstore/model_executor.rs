@@ -1,11 +1,284 @@
+use actix::Message;
 use gears::structure::common::ModelLoadError;
 use gears::structure::gxmodel::GxModel;
+use gears::validation::common::validate_gxmodel;
+use json_patch;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The stored model could not be read or written
+    Load(ModelLoadError),
+    /// The model given to the store is not a model
+    Input(ModelLoadError),
+    /// There is no such model or revision
+    NotFound(String),
+    /// The store cannot do this
+    Unsupported(String),
+    /// The stored model is no longer at any of the expected revisions
+    RevisionMismatch { expected: Vec<String>, current: String },
+    /// A patch could not be applied to the model
+    Patch(String),
+    /// The model does not validate
+    Invalid(Vec<ValidationFailure>),
+}
+
+#[derive(Debug)]
+pub struct ValidationFailure {
+    pub message: String,
+    pub paths: Vec<String>,
+}
+
+/// Who changed a model, and why
+#[derive(Clone, Debug)]
+pub struct ChangeInfo {
+    pub author: String,
+    pub message: String,
+}
+
+/// A saved revision of a model
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub revision: String,
+    pub author: String,
+    pub timestamp: String,
+    pub message: String,
+}
+
+/// A model as a store serves it, with its revision, which the store computes
+/// once when it loads the model
+#[derive(Clone, Debug)]
+pub struct StoredModel {
+    pub model: Arc<GxModel>,
+    pub revision: String,
+}
+
+/// A partial update of a model
+#[derive(Debug)]
+pub enum ModelPatch {
+    /// An RFC 6902 JSON Patch
+    Json(json_patch::Patch),
+    /// An RFC 7396 JSON Merge Patch
+    Merge(Value),
+}
+
+impl ModelPatch {
+    pub fn apply(&self, doc: &mut Value) -> Result<(), String> {
+        match *self {
+            ModelPatch::Json(ref patch) => json_patch::patch(doc, patch).map_err(|err| format!("{:?}", err)),
+            ModelPatch::Merge(ref patch) => {
+                json_patch::merge(doc, patch);
+                Ok(())
+            }
+        }
+    }
+}
 
 pub trait ModelStore {
-    fn list(&self) -> Result<Vec<GxModel>, ModelLoadError>;
-    fn get(&self, id: &str) -> Result<GxModel, ModelLoadError>;
-    fn new(&self) -> Result<GxModel, ModelLoadError>;
-    fn create(&self, json: &str) -> Result<GxModel, ModelLoadError>;
-    fn update(&self, json: &str) -> Result<GxModel, ModelLoadError>;
+    fn list(&self) -> Result<Vec<Arc<GxModel>>, ModelLoadError>;
+    /// A model, shared so that a cached model is not copied for every read
+    fn get(&self, id: &str) -> Result<Arc<GxModel>, ModelLoadError>;
+    fn new(&self) -> Result<Arc<GxModel>, ModelLoadError>;
+    fn create(&self, json: &str) -> Result<Arc<GxModel>, ModelLoadError>;
+    fn update(&self, json: &str) -> Result<Arc<GxModel>, ModelLoadError>;
     fn delete(&self, json: &str) -> Result<(), ModelLoadError>;
+    /// The current revision of a model, which changes whenever it does
+    fn revision(&self, id: &str) -> Result<String, ModelLoadError>;
+    /// Update a model, if it is still at one of the expected revisions, and
+    /// keep the new revision in its history
+    fn update_at(&self, json: &str, expected: Option<&[String]>, change: &ChangeInfo) -> Result<StoredModel, StoreError>;
+    /// Patch a model, if it is still at one of the expected revisions. The
+    /// patch applies to the JSON of the model, and the patched model must
+    /// validate unless `force` is set.
+    fn patch(
+        &self,
+        id: &str,
+        patch: &ModelPatch,
+        expected: Option<&[String]>,
+        force: bool,
+        change: &ChangeInfo,
+    ) -> Result<StoredModel, StoreError>;
+    /// The saved revisions of a model, oldest first
+    fn revisions(&self, id: &str) -> Result<Vec<RevisionInfo>, ModelLoadError>;
+    /// A model as it was at a saved revision
+    fn get_revision(&self, id: &str, revision: &str) -> Result<GxModel, ModelLoadError>;
+}
+
+/// The revision of a model, a hash of its serialized form
+pub fn revision_of(model: &GxModel) -> String {
+    let data = serde_json::to_vec(model).unwrap_or_default();
+    format!("{:x}", Sha256::digest(&data))
+}
+
+/// Check that the current revision of a model is one of the expected
+/// revisions, if any are given
+pub fn check_revision(current: &str, expected: Option<&[String]>) -> Result<(), StoreError> {
+    if let Some(expected) = expected {
+        if !expected.iter().any(|revision| revision == current) {
+            return Err(StoreError::RevisionMismatch {
+                expected: expected.to_vec(),
+                current: current.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+pub fn validate(model: &GxModel) -> Vec<ValidationFailure> {
+    validate_gxmodel(model)
+        .into_iter()
+        .map(|err| ValidationFailure {
+            message: err.message,
+            paths: err.paths,
+        })
+        .collect()
+}
+
+//
+// Messages for running a store as an actor. They own their arguments, so
+// they can be sent to a store on another thread.
+
+pub struct ModelStoreList;
+
+impl Message for ModelStoreList {
+    type Result = Result<Vec<Arc<GxModel>>, ModelLoadError>;
+}
+
+pub struct ModelStoreGet {
+    pub id: String,
+}
+
+impl Message for ModelStoreGet {
+    type Result = Result<StoredModel, ModelLoadError>;
+}
+
+pub struct ModelStoreNew;
+
+impl Message for ModelStoreNew {
+    type Result = Result<Arc<GxModel>, ModelLoadError>;
+}
+
+pub struct ModelStoreCreate {
+    pub json: String,
+}
+
+impl Message for ModelStoreCreate {
+    type Result = Result<Arc<GxModel>, ModelLoadError>;
+}
+
+pub struct ModelStoreUpdate {
+    pub json: String,
+    pub expected: Option<Vec<String>>,
+    pub change: ChangeInfo,
+}
+
+impl Message for ModelStoreUpdate {
+    type Result = Result<StoredModel, StoreError>;
+}
+
+pub struct ModelStorePatch {
+    pub id: String,
+    pub patch: ModelPatch,
+    pub expected: Option<Vec<String>>,
+    pub force: bool,
+    pub change: ChangeInfo,
+}
+
+impl Message for ModelStorePatch {
+    type Result = Result<StoredModel, StoreError>;
+}
+
+/// Delete a model, if it is still at one of the expected revisions
+pub struct ModelStoreDelete {
+    pub id: String,
+    pub expected: Option<Vec<String>>,
+}
+
+impl Message for ModelStoreDelete {
+    type Result = Result<(), StoreError>;
+}
+
+pub struct ModelStoreRevisions {
+    pub id: String,
+}
+
+impl Message for ModelStoreRevisions {
+    type Result = Result<Vec<RevisionInfo>, ModelLoadError>;
+}
+
+/// A saved revision of a model, with its details
+pub struct ModelStoreGetRevision {
+    pub id: String,
+    pub revision: String,
+}
+
+impl Message for ModelStoreGetRevision {
+    type Result = Result<(RevisionInfo, GxModel), StoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures;
+
+    #[test]
+    fn revisions_follow_the_model_content() {
+        assert_eq!(revision_of(&fixtures::model()), revision_of(&fixtures::model()));
+
+        let model = fixtures::model();
+        let mut doc = serde_json::to_value(&model).unwrap();
+        doc["name"] = json!("store");
+        let changed = GxModel::from_json(&doc.to_string()).unwrap();
+        assert_ne!(revision_of(&model), revision_of(&changed));
+    }
+
+    #[test]
+    fn models_must_be_at_an_expected_revision() {
+        let current = revision_of(&fixtures::model());
+
+        assert!(check_revision(&current, None).is_ok());
+        assert!(check_revision(&current, Some(&["other".to_owned(), current.clone()])).is_ok());
+        match check_revision(&current, Some(&["other".to_owned()])) {
+            Err(StoreError::RevisionMismatch { expected, current: found }) => {
+                assert_eq!(expected, vec!["other".to_owned()]);
+                assert_eq!(found, current);
+            }
+            other => panic!("expected a revision mismatch, got {:?}", other),
+        }
+    }
+
+    fn json_patch(ops: Value) -> ModelPatch {
+        ModelPatch::Json(serde_json::from_value(ops).unwrap())
+    }
+
+    #[test]
+    fn json_patches_are_applied() {
+        let mut doc = json!({"name": "shop", "body": {"xflows": []}});
+        json_patch(json!([
+            {"op": "replace", "path": "/name", "value": "store"},
+            {"op": "add", "path": "/body/xflows/-", "value": {"name": "login"}},
+        ]))
+        .apply(&mut doc)
+        .unwrap();
+        assert_eq!(doc, json!({"name": "store", "body": {"xflows": [{"name": "login"}]}}));
+    }
+
+    #[test]
+    fn failing_json_patches_are_errors() {
+        let mut doc = json!({"name": "shop"});
+        let patch = json_patch(json!([{"op": "test", "path": "/name", "value": "store"}]));
+        assert!(patch.apply(&mut doc).is_err());
+        assert!(json_patch(json!([{"op": "remove", "path": "/body"}])).apply(&mut doc).is_err());
+    }
+
+    #[test]
+    fn merge_patches_are_applied() {
+        let mut doc = json!({"name": "shop", "version": 1, "body": {"locale": "en_US"}});
+        ModelPatch::Merge(json!({"version": null, "body": {"locale": "nl_NL"}}))
+            .apply(&mut doc)
+            .unwrap();
+        assert_eq!(doc, json!({"name": "shop", "body": {"locale": "nl_NL"}}));
+    }
 }
@@ -3,8 +3,9 @@ use futures::Future;
 use gears;
 use gears::structure::common::ModelLoadError;
 use gears::structure::gxmodel::GxModel;
+use std::sync::Arc;
 
-use super::model_executor::ModelStore;
+use super::model_executor::{ChangeInfo, ModelPatch, ModelStore, RevisionInfo, StoreError, StoredModel};
 
 #[derive(Clone)]
 pub struct SQLliteModelStore {
@@ -17,28 +18,60 @@ impl SQLliteModelStore {
     }
 }
 
+/// The error for what the SQLite store cannot do yet
+fn unsupported(what: &str) -> ModelLoadError {
+    ModelLoadError::InputError(format!("{} is not supported by the SQLite store", what))
+}
+
 impl ModelStore for SQLliteModelStore {
-    fn list(&self) -> Result<Vec<GxModel>, ModelLoadError> {
+    fn list(&self) -> Result<Vec<Arc<GxModel>>, ModelLoadError> {
         unimplemented!()
     }
 
-    fn get(&self, _id: &str) -> Result<GxModel, ModelLoadError> {
+    fn get(&self, _id: &str) -> Result<Arc<GxModel>, ModelLoadError> {
         unimplemented!()
     }
 
-    fn new(&self) -> Result<GxModel, ModelLoadError> {
+    fn new(&self) -> Result<Arc<GxModel>, ModelLoadError> {
         unimplemented!()
     }
 
-    fn create(&self, json: &str) -> Result<GxModel, ModelLoadError> {
+    fn create(&self, json: &str) -> Result<Arc<GxModel>, ModelLoadError> {
         unimplemented!()
     }
 
-    fn update(&self, json: &str) -> Result<GxModel, ModelLoadError> {
+    fn update(&self, json: &str) -> Result<Arc<GxModel>, ModelLoadError> {
         unimplemented!()
     }
 
     fn delete(&self, json: &str) -> Result<(), ModelLoadError> {
         unimplemented!()
     }
+
+    fn revision(&self, id: &str) -> Result<String, ModelLoadError> {
+        Err(unsupported("Revisions"))
+    }
+
+    fn update_at(&self, json: &str, expected: Option<&[String]>, change: &ChangeInfo) -> Result<StoredModel, StoreError> {
+        Err(StoreError::Unsupported("Revisions are not supported by the SQLite store".to_owned()))
+    }
+
+    fn patch(
+        &self,
+        id: &str,
+        patch: &ModelPatch,
+        expected: Option<&[String]>,
+        force: bool,
+        change: &ChangeInfo,
+    ) -> Result<StoredModel, StoreError> {
+        Err(StoreError::Unsupported("Patching is not supported by the SQLite store".to_owned()))
+    }
+
+    fn revisions(&self, id: &str) -> Result<Vec<RevisionInfo>, ModelLoadError> {
+        Err(unsupported("Revisions"))
+    }
+
+    fn get_revision(&self, id: &str, revision: &str) -> Result<GxModel, ModelLoadError> {
+        Err(unsupported("Revisions"))
+    }
 }
@@ -0,0 +1,585 @@
+use gears::structure::gxmodel::GxModel;
+use jsonapi::model::JsonApiModel;
+use serde_json::{self, Map, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use apierror::ApiError;
+use diff::{key_of, model_to_value};
+
+static DEFAULT_PAGE_SIZE: usize = 20;
+static MAX_PAGE_SIZE: usize = 100;
+
+/// The model sub-documents exposed as related resources, with the relationship
+/// name, the resource type, the pointer to the documents in the model and the
+/// field identifying them
+static RELATIONSHIPS: &'static [(&'static str, &'static str, &'static str, &'static str)] = &[
+    ("xflows", "xflow", "/body/xflows", "id"),
+    ("pages", "page", "/body/pages", "id"),
+    ("entities", "entity", "/body/domain/body/entities", "name"),
+    ("translations", "translation", "/body/translations", "id"),
+];
+
+/// The JSON:API query parameters of a collection request
+#[derive(Debug)]
+pub struct Query {
+    pub page_number: usize,
+    pub page_size: usize,
+    /// Fields to sort on, with `true` for descending
+    pub sort: Vec<(String, bool)>,
+    /// Fields with the values they may have, from `filter[field]=a,b`
+    pub filters: Vec<(String, Vec<String>)>,
+    /// Sparse fieldsets per resource type, from `fields[type]=a,b`
+    pub fields: HashMap<String, Vec<String>>,
+    /// Relationships to return as compound documents, from `include=a,b`
+    pub include: Vec<String>,
+    params: BTreeMap<String, String>,
+}
+
+fn bracketed<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    if key.starts_with(prefix) && key.ends_with(']') && key.len() > prefix.len() + 2 {
+        let inner = &key[prefix.len()..];
+        if inner.starts_with('[') {
+            return Some(&inner[1..inner.len() - 1]);
+        }
+    }
+    None
+}
+
+fn list(val: &str) -> Vec<String> {
+    val.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_owned())
+        .collect()
+}
+
+fn positive(key: &str, val: &str) -> Result<usize, ApiError> {
+    match val.parse::<usize>() {
+        Ok(num) if num > 0 => Ok(num),
+        _ => Err(ApiError::parameter(
+            key,
+            &format!("'{}' must be a positive number, not '{}'", key, val),
+        )),
+    }
+}
+
+impl Query {
+    /// A query for a single model, with the given relationships included
+    pub fn including(include: &[String]) -> Query {
+        Query {
+            page_number: 1,
+            page_size: DEFAULT_PAGE_SIZE,
+            sort: Vec::new(),
+            filters: Vec::new(),
+            fields: HashMap::new(),
+            include: include.to_vec(),
+            params: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Query, ApiError> {
+        let mut query = Query::including(&[]);
+        query.params = params.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+
+        for (key, val) in params {
+            if key == "sort" {
+                query.sort = list(val)
+                    .into_iter()
+                    .map(|field| {
+                        if field.starts_with('-') {
+                            (field[1..].to_owned(), true)
+                        } else {
+                            (field, false)
+                        }
+                    })
+                    .collect();
+            } else if key == "include" {
+                query.include = list(val);
+                for name in &query.include {
+                    if !RELATIONSHIPS.iter().any(|rel| rel.0 == name.as_str()) {
+                        return Err(ApiError::parameter(
+                            key,
+                            &format!("Unknown relationship '{}' in include", name),
+                        ));
+                    }
+                }
+            } else if let Some(name) = bracketed(key, "page") {
+                match name {
+                    "number" => query.page_number = positive(key, val)?,
+                    "size" => query.page_size = positive(key, val)?.min(MAX_PAGE_SIZE),
+                    _ => {
+                        return Err(ApiError::parameter(
+                            key,
+                            &format!("Unsupported page parameter '{}'", key),
+                        ))
+                    }
+                }
+            } else if let Some(name) = bracketed(key, "filter") {
+                query.filters.push((name.to_owned(), list(val)));
+            } else if let Some(name) = bracketed(key, "fields") {
+                query.fields.insert(name.to_owned(), list(val));
+            }
+        }
+
+        Ok(query)
+    }
+
+    /// The URL of a page of this collection, keeping the other parameters
+    fn page_url(&self, path: &str, number: usize) -> String {
+        let mut params = self.params.clone();
+        params.insert("page[number]".to_owned(), number.to_string());
+        params.insert("page[size]".to_owned(), self.page_size.to_string());
+
+        let query: Vec<String> = params
+            .iter()
+            .map(|(key, val)| format!("{}={}", encode(key), encode(val)))
+            .collect();
+        format!("{}?{}", path, query.join("&"))
+    }
+}
+
+fn encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'_' | b'.' | b'~' | b'[' | b']' | b',' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Remove the member a pointer such as `/body/xflows` points to
+fn remove_pointer(doc: &mut Value, pointer: &str) {
+    if let Some(idx) = pointer.rfind('/') {
+        let (parent, member) = (&pointer[..idx], &pointer[idx + 1..]);
+        if let Some(parent) = doc.pointer_mut(parent).and_then(|parent| parent.as_object_mut()) {
+            parent.remove(member);
+        }
+    }
+}
+
+/// The attributes of a model resource, the model without its id and without
+/// the sub-documents that are served as relationships
+fn model_attributes(doc: &Value) -> Value {
+    let mut attributes = doc.clone();
+    if let Some(attributes) = attributes.as_object_mut() {
+        attributes.remove("id");
+    }
+    for &(_, _, pointer, _) in RELATIONSHIPS {
+        remove_pointer(&mut attributes, pointer);
+    }
+    attributes
+}
+
+/// The JSON:API resource object of a model, with a relationships block
+/// linking to its sub-documents
+pub fn model_resource(model: &GxModel, doc: &Value, path: &str) -> Value {
+    let mut resource = serde_json::to_value(&model.to_jsonapi_document())
+        .ok()
+        .and_then(|doc| doc.get("data").cloned())
+        .unwrap_or(Value::Null);
+    resource["attributes"] = model_attributes(doc);
+
+    let mut relationships = Map::new();
+    for &(name, _, _, _) in RELATIONSHIPS {
+        let data: Vec<Value> = related(doc, name)
+            .unwrap_or_default()
+            .iter()
+            .map(|resource| json!({"type": resource["type"], "id": resource["id"]}))
+            .collect();
+        relationships.insert(
+            name.to_owned(),
+            json!({
+                "links": {"related": format!("{}/{}", path, name)},
+                "data": data,
+            }),
+        );
+    }
+    if let Some(resource) = resource.as_object_mut() {
+        resource.insert("relationships".to_owned(), Value::Object(relationships));
+        resource.insert("links".to_owned(), json!({ "self": path }));
+    }
+    resource
+}
+
+/// The sub-documents of a model for a relationship as resource objects, or
+/// `None` for an unknown relationship
+pub fn related(doc: &Value, name: &str) -> Option<Vec<Value>> {
+    let &(_, kind, pointer, key) = RELATIONSHIPS.iter().find(|rel| rel.0 == name)?;
+    let docs = doc
+        .pointer(pointer)
+        .and_then(|docs| docs.as_array())
+        .map_or(Vec::new(), |docs| {
+            docs.iter()
+                .map(|item| {
+                    let mut attributes = item.clone();
+                    if let Some(attributes) = attributes.as_object_mut() {
+                        if key == "id" {
+                            attributes.remove("id");
+                        }
+                    }
+                    json!({
+                        "type": kind,
+                        "id": key_of(item, key),
+                        "attributes": attributes,
+                    })
+                })
+                .collect()
+        });
+    Some(docs)
+}
+
+/// The resources for the relationships a query includes
+fn included(doc: &Value, query: &Query) -> Vec<Value> {
+    query
+        .include
+        .iter()
+        .filter_map(|name| related(doc, name))
+        .flat_map(|resources| resources.into_iter())
+        .collect()
+}
+
+/// A JSON:API document for a single model, with the included resources
+pub fn model_document(model: &GxModel, query: &Query, path: &str) -> Value {
+    document_of(model, &model_to_value(model), query, path)
+}
+
+fn document_of(model: &GxModel, doc: &Value, query: &Query, path: &str) -> Value {
+    let mut data = model_resource(model, doc, path);
+    sparse(&mut data, &query.fields);
+    let included: Vec<Value> = included(doc, query)
+        .into_iter()
+        .map(|mut resource| {
+            sparse(&mut resource, &query.fields);
+            resource
+        })
+        .collect();
+
+    let mut document = json!({
+        "data": data,
+        "links": { "self": path },
+    });
+    if !query.include.is_empty() {
+        document["included"] = Value::Array(included);
+    }
+    document
+}
+
+/// A JSON:API collection of the models, with the included resources of the
+/// models on the requested page
+pub fn model_collection(models: &[Arc<GxModel>], query: &Query, path: &str) -> Result<Value, ApiError> {
+    let mut docs = HashMap::new();
+    let mut resources = Vec::new();
+    for model in models {
+        let doc = model_to_value(model);
+        let resource = model_resource(model, &doc, &format!("{}/{}", path, model.id));
+        docs.insert(key_of(&resource, "id"), doc);
+        resources.push(resource);
+    }
+    collection(resources, query, path, |resource| {
+        docs.get(&key_of(resource, "id"))
+            .map_or(Vec::new(), |doc| included(doc, query))
+    })
+}
+
+/// A field of a resource, `id`, `type` or an attribute, with dots for nested
+/// attributes such as `header.name`
+fn field<'a>(resource: &'a Value, name: &str) -> Option<&'a Value> {
+    if name == "id" || name == "type" {
+        return resource.get(name);
+    }
+    name.split('.')
+        .fold(resource.get("attributes"), |val, part| val.and_then(|val| val.get(part)))
+}
+
+fn compare(left: Option<&Value>, right: Option<&Value>) -> Ordering {
+    match (left, right) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(left), Some(right)) => match (left.as_f64(), right.as_f64()) {
+            (Some(left), Some(right)) => left.partial_cmp(&right).unwrap_or(Ordering::Equal),
+            _ => match (left.as_str(), right.as_str()) {
+                (Some(left), Some(right)) => left.cmp(right),
+                _ => left.to_string().cmp(&right.to_string()),
+            },
+        },
+    }
+}
+
+fn matches(val: Option<&Value>, wanted: &[String]) -> bool {
+    match val {
+        Some(&Value::String(ref text)) => wanted.iter().any(|want| want == text),
+        Some(other) => wanted.iter().any(|want| *want == other.to_string()),
+        None => false,
+    }
+}
+
+/// Drop the attributes and relationships not in the sparse fieldset for the
+/// type of a resource
+fn sparse(resource: &mut Value, fields: &HashMap<String, Vec<String>>) {
+    let wanted = match resource
+        .get("type")
+        .and_then(|kind| kind.as_str())
+        .and_then(|kind| fields.get(kind))
+    {
+        Some(wanted) => wanted.clone(),
+        None => return,
+    };
+    for member in &["attributes", "relationships"] {
+        if let Some(values) = resource.get_mut(*member).and_then(|values| values.as_object_mut()) {
+            let kept: Map<String, Value> = values
+                .iter()
+                .filter(|&(key, _)| wanted.contains(key))
+                .map(|(key, val)| (key.clone(), val.clone()))
+                .collect();
+            *values = kept;
+        }
+    }
+}
+
+/// Filter, sort and paginate resources into a JSON:API collection document
+/// with pagination links and the total count. `included` gives the resources
+/// to include for a resource on the page. Sorting on a field none of the
+/// resources have is an error.
+pub fn collection<F>(resources: Vec<Value>, query: &Query, path: &str, included: F) -> Result<Value, ApiError>
+where
+    F: Fn(&Value) -> Vec<Value>,
+{
+    if !resources.is_empty() {
+        for &(ref name, _) in &query.sort {
+            if !resources.iter().any(|resource| field(resource, name).is_some()) {
+                return Err(ApiError::invalid_sort(name));
+            }
+        }
+    }
+
+    let mut resources: Vec<Value> = resources
+        .into_iter()
+        .filter(|resource| {
+            query
+                .filters
+                .iter()
+                .all(|&(ref name, ref wanted)| matches(field(resource, name), wanted))
+        })
+        .collect();
+
+    resources.sort_by(|left, right| {
+        query
+            .sort
+            .iter()
+            .map(|&(ref name, descending)| {
+                let order = compare(field(left, name), field(right, name));
+                if descending {
+                    order.reverse()
+                } else {
+                    order
+                }
+            })
+            .find(|order| *order != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    });
+
+    let total = resources.len();
+    let last = ((total + query.page_size - 1) / query.page_size).max(1);
+    // Page numbers past the end give an empty page rather than overflowing
+    let page: Vec<Value> = resources
+        .into_iter()
+        .skip((query.page_number - 1).saturating_mul(query.page_size))
+        .take(query.page_size)
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut compound = Vec::new();
+    for resource in &page {
+        for mut related in included(resource) {
+            if seen.insert((key_of(&related, "type"), key_of(&related, "id"))) {
+                sparse(&mut related, &query.fields);
+                compound.push(related);
+            }
+        }
+    }
+
+    let data: Vec<Value> = page
+        .into_iter()
+        .map(|mut resource| {
+            sparse(&mut resource, &query.fields);
+            resource
+        })
+        .collect();
+
+    let mut links = Map::new();
+    links.insert("self".to_owned(), json!(query.page_url(path, query.page_number)));
+    links.insert("first".to_owned(), json!(query.page_url(path, 1)));
+    links.insert("last".to_owned(), json!(query.page_url(path, last)));
+    if query.page_number > 1 {
+        links.insert("prev".to_owned(), json!(query.page_url(path, query.page_number - 1)));
+    }
+    if query.page_number < last {
+        links.insert("next".to_owned(), json!(query.page_url(path, query.page_number + 1)));
+    }
+
+    let mut document = json!({
+        "data": data,
+        "links": links,
+        "meta": {
+            "total": total,
+            "pages": last,
+        },
+    });
+    if !query.include.is_empty() {
+        document["included"] = Value::Array(compound);
+    }
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(params: &[(&str, &str)]) -> Result<Query, ApiError> {
+        let params: HashMap<String, String> = params
+            .iter()
+            .map(|&(key, val)| (key.to_owned(), val.to_owned()))
+            .collect();
+        Query::from_params(&params)
+    }
+
+    fn resources(count: usize) -> Vec<Value> {
+        (0..count)
+            .map(|idx| {
+                json!({
+                    "type": "model",
+                    "id": idx.to_string(),
+                    "attributes": {"version": idx, "header": {"name": format!("model {}", idx % 3)}},
+                })
+            })
+            .collect()
+    }
+
+    fn ids(doc: &Value) -> Vec<String> {
+        doc["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|resource| key_of(resource, "id"))
+            .collect()
+    }
+
+    fn model_doc() -> Value {
+        json!({
+            "id": "m1",
+            "name": "shop",
+            "body": {
+                "domain": {"id": "d1", "body": {"events": [], "entities": [{"name": "item", "attributes": []}]}},
+                "xflows": [{"id": "x1", "name": "flow"}],
+                "pages": [],
+                "translations": [],
+            },
+        })
+    }
+
+    #[test]
+    fn model_attributes_leave_out_the_relationships() {
+        let attributes = model_attributes(&model_doc());
+        assert_eq!(
+            attributes,
+            json!({
+                "name": "shop",
+                "body": {"domain": {"id": "d1", "body": {"events": []}}},
+            })
+        );
+    }
+
+    #[test]
+    fn related_documents_are_resources() {
+        let doc = model_doc();
+        assert_eq!(
+            related(&doc, "xflows").unwrap(),
+            vec![json!({"type": "xflow", "id": "x1", "attributes": {"name": "flow"}})]
+        );
+        assert_eq!(
+            related(&doc, "entities").unwrap(),
+            vec![json!({"type": "entity", "id": "item", "attributes": {"name": "item", "attributes": []}})]
+        );
+        assert!(related(&doc, "unknown").is_none());
+    }
+
+    #[test]
+    fn query_parameters_are_parsed() {
+        let query = parse(&[
+            ("page[number]", "2"),
+            ("page[size]", "500"),
+            ("sort", "-version,id"),
+            ("filter[header.name]", "a,b"),
+            ("include", "xflows"),
+        ])
+        .unwrap();
+
+        assert_eq!(query.page_number, 2);
+        assert_eq!(query.page_size, MAX_PAGE_SIZE);
+        assert_eq!(query.sort, vec![("version".to_owned(), true), ("id".to_owned(), false)]);
+        assert_eq!(query.filters, vec![("header.name".to_owned(), vec!["a".to_owned(), "b".to_owned()])]);
+        assert_eq!(query.include, vec!["xflows".to_owned()]);
+    }
+
+    #[test]
+    fn invalid_query_parameters_are_rejected() {
+        assert_eq!(parse(&[("page[number]", "0")]).unwrap_err().code, "invalid_parameter");
+        assert_eq!(parse(&[("page[size]", "-1")]).unwrap_err().code, "invalid_parameter");
+        assert_eq!(parse(&[("page[offset]", "1")]).unwrap_err().code, "invalid_parameter");
+        assert_eq!(parse(&[("include", "widgets")]).unwrap_err().code, "invalid_parameter");
+        assert_eq!(
+            parse(&[("page[number]", "99999999999999999999999")]).unwrap_err().code,
+            "invalid_parameter"
+        );
+    }
+
+    #[test]
+    fn collections_are_sorted_filtered_and_paginated() {
+        let query = parse(&[("sort", "-version"), ("page[size]", "2"), ("page[number]", "2")]).unwrap();
+        let doc = collection(resources(5), &query, "/model", |_| Vec::new()).unwrap();
+
+        assert_eq!(ids(&doc), vec!["2", "1"]);
+        assert_eq!(doc["meta"]["total"], json!(5));
+        assert_eq!(doc["meta"]["pages"], json!(3));
+        assert!(doc["links"]["prev"].is_string());
+        assert!(doc["links"]["next"].is_string());
+
+        let query = parse(&[("filter[header.name]", "model 1")]).unwrap();
+        let doc = collection(resources(5), &query, "/model", |_| Vec::new()).unwrap();
+        assert_eq!(ids(&doc), vec!["1", "4"]);
+    }
+
+    #[test]
+    fn pages_past_the_end_are_empty() {
+        let number = ::std::usize::MAX.to_string();
+        let query = parse(&[("page[number]", number.as_str()), ("page[size]", "100")]).unwrap();
+        let doc = collection(resources(3), &query, "/model", |_| Vec::new()).unwrap();
+
+        assert!(ids(&doc).is_empty());
+        assert!(doc["links"].get("next").is_none());
+    }
+
+    #[test]
+    fn unknown_sort_fields_are_rejected() {
+        let query = parse(&[("sort", "colour")]).unwrap();
+        let err = collection(resources(3), &query, "/model", |_| Vec::new()).unwrap_err();
+
+        assert_eq!(err.status, "400");
+        assert_eq!(err.code, "invalid_sort");
+    }
+
+    #[test]
+    fn page_links_are_encoded() {
+        let query = parse(&[("filter[header.name]", "a b&c")]).unwrap();
+        let url = query.page_url("/model", 2);
+
+        assert!(url.contains("filter[header.name]=a%20b%26c"));
+        assert!(url.contains("page[number]=2"));
+    }
+}
@@ -0,0 +1,341 @@
+use serde_json::Value;
+
+/// A part of a model, each item holding a JSON pointer into the model and the
+/// value found there
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fragment {
+    pub model_id: String,
+    pub items: Vec<FragmentItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FragmentItem {
+    pub pointer: String,
+    pub value: Value,
+}
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    Field(String),
+    Index(usize),
+    All,
+    Match(String, String),
+}
+
+fn escape(token: &str) -> String {
+    token.replace("~", "~0").replace("/", "~1")
+}
+
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+fn matches(val: &Value, expected: &str) -> bool {
+    match *val {
+        Value::String(ref s) => s == expected,
+        ref other => other.to_string() == expected,
+    }
+}
+
+/// Select the documents of the given body kinds, such as `xflows` or
+/// `domain`, optionally narrowed down to the given document ids. No kinds
+/// means all kinds.
+pub fn select_documents(model: &Value, kinds: &[&str], ids: &[&str]) -> Vec<FragmentItem> {
+    let mut items = Vec::new();
+    let body = match model.get("body").and_then(|body| body.as_object()) {
+        Some(body) => body,
+        None => return items,
+    };
+
+    let wanted = |doc: &Value| {
+        ids.is_empty() || doc.get("id").map_or(false, |id| ids.iter().any(|want| matches(id, want)))
+    };
+
+    for (kind, docs) in body {
+        if !kinds.is_empty() && !kinds.contains(&kind.as_str()) {
+            continue;
+        }
+        let pointer = format!("/body/{}", escape(kind));
+        match *docs {
+            Value::Array(ref docs) => {
+                for (idx, doc) in docs.iter().enumerate() {
+                    if wanted(doc) {
+                        items.push(FragmentItem {
+                            pointer: format!("{}/{}", pointer, idx),
+                            value: doc.clone(),
+                        });
+                    }
+                }
+            }
+            ref doc => {
+                if wanted(doc) {
+                    items.push(FragmentItem {
+                        pointer: pointer,
+                        value: doc.clone(),
+                    });
+                }
+            }
+        }
+    }
+    items
+}
+
+/// Evaluate a JSONPath-like selector such as `$.body.xflows[name=login]`,
+/// `body.pages[0]` or `body.domain.body.entities[*]`. Brackets hold an
+/// index, `*` for all children, or a `key=value` filter on array items.
+pub fn select_path(model: &Value, expr: &str) -> Result<Vec<FragmentItem>, String> {
+    let steps = parse(expr)?;
+    if steps.is_empty() {
+        return Err(format!("Selector '{}' selects the whole model, export it without --select", expr));
+    }
+    let mut current: Vec<(String, &Value)> = vec![(String::new(), model)];
+
+    for step in &steps {
+        let mut next = Vec::new();
+        for (pointer, val) in current {
+            match *step {
+                Step::Field(ref name) => {
+                    if let Some(child) = val.get(name.as_str()) {
+                        next.push((format!("{}/{}", pointer, escape(name)), child));
+                    }
+                }
+                Step::Index(idx) => {
+                    if let Some(child) = val.get(idx) {
+                        next.push((format!("{}/{}", pointer, idx), child));
+                    }
+                }
+                Step::All => match *val {
+                    Value::Array(ref items) => {
+                        for (idx, child) in items.iter().enumerate() {
+                            next.push((format!("{}/{}", pointer, idx), child));
+                        }
+                    }
+                    Value::Object(ref map) => {
+                        for (key, child) in map {
+                            next.push((format!("{}/{}", pointer, escape(key)), child));
+                        }
+                    }
+                    _ => {}
+                },
+                Step::Match(ref key, ref expected) => {
+                    if let Value::Array(ref items) = *val {
+                        for (idx, child) in items.iter().enumerate() {
+                            if child.get(key.as_str()).map_or(false, |v| matches(v, expected)) {
+                                next.push((format!("{}/{}", pointer, idx), child));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        current = next;
+    }
+
+    Ok(current
+        .into_iter()
+        .map(|(pointer, val)| FragmentItem {
+            pointer: pointer,
+            value: val.clone(),
+        })
+        .collect())
+}
+
+fn parse(expr: &str) -> Result<Vec<Step>, String> {
+    let trimmed = expr.trim();
+    let trimmed = if trimmed.starts_with('$') {
+        &trimmed[1..]
+    } else {
+        trimmed
+    };
+
+    let mut steps = Vec::new();
+    let mut chars = trimmed.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {}
+            '[' => {
+                let mut inner = String::new();
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => inner.push(c),
+                        None => return Err(format!("Unclosed '[' in selector '{}'", expr)),
+                    }
+                }
+                steps.push(parse_filter(inner.trim())?);
+            }
+            c => {
+                let mut name = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next == '.' || next == '[' {
+                        break;
+                    }
+                    name.push(next);
+                    chars.next();
+                }
+                if name == "*" {
+                    steps.push(Step::All);
+                } else {
+                    steps.push(Step::Field(name));
+                }
+            }
+        }
+    }
+    Ok(steps)
+}
+
+fn parse_filter(inner: &str) -> Result<Step, String> {
+    if inner == "*" {
+        return Ok(Step::All);
+    }
+    if let Ok(idx) = inner.parse::<usize>() {
+        return Ok(Step::Index(idx));
+    }
+    match inner.find('=') {
+        Some(pos) => {
+            let key = inner[..pos].trim();
+            let val = inner[pos + 1..].trim().trim_matches(|c: char| c == '\'' || c == '"');
+            Ok(Step::Match(key.to_owned(), val.to_owned()))
+        }
+        None => Err(format!("Invalid selector filter '[{}]'", inner)),
+    }
+}
+
+/// Apply a fragment to a model. Array items with an `id` replace the item
+/// with the same id, wherever it is in the array, or are appended if there is
+/// none. Other values replace the value at their pointer.
+pub fn apply(model: &mut Value, fragment: &Fragment) -> Result<Vec<String>, String> {
+    let mut report = Vec::new();
+    for item in &fragment.items {
+        report.push(apply_item(model, item)?);
+    }
+    Ok(report)
+}
+
+fn apply_item(model: &mut Value, item: &FragmentItem) -> Result<String, String> {
+    // An empty pointer is the whole model, which a fragment never replaces
+    let pos = match item.pointer.rfind('/') {
+        Some(pos) if item.pointer.starts_with('/') => pos,
+        _ => return Err(format!("Invalid pointer '{}', fragments cannot replace the whole model", item.pointer)),
+    };
+    let parent_pointer = &item.pointer[..pos];
+    let last = unescape(&item.pointer[pos + 1..]);
+
+    let parent = match model.pointer_mut(parent_pointer) {
+        Some(parent) => parent,
+        None => return Err(format!("No '{}' in model", parent_pointer)),
+    };
+
+    match *parent {
+        Value::Array(ref mut items) => {
+            let idx = match item.value.get("id") {
+                Some(id) => items.iter().position(|existing| existing.get("id") == Some(id)),
+                None => last.parse::<usize>().ok().filter(|&idx| idx < items.len()),
+            };
+            match idx {
+                Some(idx) => {
+                    items[idx] = item.value.clone();
+                    Ok(format!("updated   {}/{}", parent_pointer, idx))
+                }
+                None => {
+                    items.push(item.value.clone());
+                    Ok(format!("added     {}/{}", parent_pointer, items.len() - 1))
+                }
+            }
+        }
+        Value::Object(ref mut map) => match map.insert(last, item.value.clone()) {
+            Some(_) => Ok(format!("updated   {}", item.pointer)),
+            None => Ok(format!("added     {}", item.pointer)),
+        },
+        _ => Err(format!("'{}' is not an object or array", parent_pointer)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Value {
+        json!({
+            "id": "m1",
+            "body": {
+                "domain": {"id": "d1"},
+                "xflows": [{"id": "x1", "name": "login"}, {"id": "x2", "name": "logout"}],
+            },
+        })
+    }
+
+    fn pointers(items: &[FragmentItem]) -> Vec<&str> {
+        items.iter().map(|item| item.pointer.as_str()).collect()
+    }
+
+    #[test]
+    fn selectors_are_parsed() {
+        assert_eq!(
+            parse("$.body.xflows[name='login']").unwrap(),
+            vec![
+                Step::Field("body".to_owned()),
+                Step::Field("xflows".to_owned()),
+                Step::Match("name".to_owned(), "login".to_owned()),
+            ]
+        );
+        assert_eq!(
+            parse("body.pages[0]").unwrap(),
+            vec![Step::Field("body".to_owned()), Step::Field("pages".to_owned()), Step::Index(0)]
+        );
+        assert!(parse("body.xflows[0").is_err());
+        assert!(parse("body.xflows[name]").is_err());
+    }
+
+    #[test]
+    fn paths_select_pointers() {
+        let model = model();
+        assert_eq!(pointers(&select_path(&model, "$.body.xflows[name=logout]").unwrap()), vec!["/body/xflows/1"]);
+        assert_eq!(pointers(&select_path(&model, "body.xflows[*]").unwrap()), vec!["/body/xflows/0", "/body/xflows/1"]);
+        assert!(select_path(&model, "$").is_err());
+    }
+
+    #[test]
+    fn documents_are_selected_by_kind_and_id() {
+        let model = model();
+        assert_eq!(pointers(&select_documents(&model, &["domain"], &[])), vec!["/body/domain"]);
+        assert_eq!(pointers(&select_documents(&model, &[], &["x2"])), vec!["/body/xflows/1"]);
+    }
+
+    #[test]
+    fn fragments_replace_documents_by_id() {
+        let mut model = model();
+        let fragment = Fragment {
+            model_id: "m1".to_owned(),
+            items: vec![
+                FragmentItem {
+                    pointer: "/body/xflows/0".to_owned(),
+                    value: json!({"id": "x2", "name": "sign out"}),
+                },
+                FragmentItem {
+                    pointer: "/body/xflows/5".to_owned(),
+                    value: json!({"id": "x3", "name": "register"}),
+                },
+            ],
+        };
+        let report = apply(&mut model, &fragment).unwrap();
+
+        assert_eq!(report, vec!["updated   /body/xflows/1", "added     /body/xflows/2"]);
+        assert_eq!(model["body"]["xflows"][1]["name"], json!("sign out"));
+        assert_eq!(model["body"]["xflows"][2]["name"], json!("register"));
+    }
+
+    #[test]
+    fn fragments_cannot_replace_the_model() {
+        let mut model = model();
+        let fragment = Fragment {
+            model_id: "m1".to_owned(),
+            items: vec![FragmentItem {
+                pointer: String::new(),
+                value: json!({}),
+            }],
+        };
+        assert!(apply(&mut model, &fragment).is_err());
+        assert_eq!(model, self::model());
+    }
+}
@@ -2,24 +2,42 @@
 //!
 //! A simple example integrating juniper in actix-web
 
+use gears::structure::common::ModelLoadError;
+use gears::structure::gxmodel::GxModel;
 use gears::structure::model::ModelDocument;
 
+use actix_web::dev::Resource;
+use actix_web::middleware::cors::{Cors, CorsBuilder};
+use actix_web::middleware::{Finished, Middleware, Response, Started};
 use actix_web::{
-    http::Method, http::StatusCode, middleware, pred, server, App, AsyncResponder, FutureResponse,
-    HttpMessage, HttpRequest, HttpResponse, Json,
+    error, http::header, http::header::HeaderName, http::header::HeaderValue, http::Method,
+    http::StatusCode, http::Uri, middleware, pred, server, App, AsyncResponder, FutureResponse, HttpMessage, HttpRequest, HttpResponse,
 };
 use actix_web_middleware_opa::{HTTPTokenAuthRequest, OPAResponse, PolicyVerifier};
-use futures::future::Future;
+use actix::dev::ToEnvelope;
+use actix::{Addr, Handler, Message, SyncArbiter, SyncContext};
+use futures::future::{self, Future};
+use futures::Stream;
 
-use bytes::Bytes;
 use modelstore::filesystem::FileSystemModelStore;
-use modelstore::model_executor::ModelStore;
+use modelstore::model_executor::{
+    validate, ChangeInfo, ModelPatch, ModelStore, ModelStoreDelete, ModelStoreGet,
+    ModelStoreGetRevision, ModelStoreList, ModelStorePatch, ModelStoreRevisions, ModelStoreUpdate,
+    RevisionInfo, StoreError, StoredModel,
+};
 use std::str;
+use std::time::Duration;
 
 use jsonapi::model::JsonApiModel;
 use jsonapi::api::JsonApiDocument;
 
-use app::ServerConfig;
+use apierror::{error_response, ApiError, CONTENT_TYPE_JSONAPI};
+use app::{CorsConfig, Format, ServerConfig};
+use auth::{self, Identity, JwtAuth};
+use diff;
+use feed::ChangeFeed;
+use formats;
+use resource::{self, Query};
 
 #[derive(Deserialize)]
 struct PolicyDecision {
@@ -38,131 +56,229 @@ impl OPAResponse for PolicyDecision {
 }
 
 struct AppState {
-    modelstore: FileSystemModelStore,
+    store: Addr<FileSystemModelStore>,
+    config: ServerConfig,
+    feed: ChangeFeed,
 }
 
+/// Threads running the model store, so that disk I/O does not block the
+/// HTTP workers
+static STORE_THREADS: usize = 4;
+
 static CONTENT_TYPE_JSON: &'static str = "application/json; charset=utf-8";
 
+/// The default access log format with the path instead of the request line,
+/// so that tokens given as `access_token` are not logged
+static LOG_FORMAT: &'static str = r#"%a "%U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
 type Verifier = PolicyVerifier<HTTPTokenAuthRequest, PolicyDecision>;
 
-pub fn serve(path: &str, config: &ServerConfig) {
+pub fn serve(path: &str, config: &ServerConfig) -> Result<(), String> {
     ::std::env::set_var("RUST_LOG", "actix_web=info");
     let sys = actix::System::new("model-jsonapi");
     let config = config.clone();
 
-    let modelstore = match FileSystemModelStore::new(&path) {
-        Ok(res) => res,
-        Err(_) => {
-            error!("Unable to initialize model. Is this a model direcory?");
-            return ();
-        }
-    };
+    let modelstore = FileSystemModelStore::new(&path)
+        .map_err(|_| "Unable to initialize model. Is this a model direcory?".to_owned())?;
+
+    let feed = ChangeFeed::new();
+    if let Ok(stored) = modelstore.cached() {
+        feed.check(&stored.model, &stored.revision);
+    }
+    feed.keepalive(Duration::from_secs(15));
+
+    // Changes on disk and through the API, on the watcher and store threads
+    let watched = modelstore.clone();
+    let watch_feed = feed.clone();
+    let watching = modelstore.watch(move || match watched.cached() {
+        Ok(stored) => watch_feed.check(&stored.model, &stored.revision),
+        Err(err) => error!("serve: unable to reload the model: {:?}", err),
+    });
+    if let Err(err) = watching {
+        error!("serve: unable to watch the model for changes: {}", err);
+    }
+
+    let arbiter_store = modelstore.clone();
+    let store = SyncArbiter::start(STORE_THREADS, move || arbiter_store.clone());
 
     if config.enable_opa && config.opa_url.is_none() {
-        error!("serve: enable_opa is set but no URL for OPA is configured, aborting");
-        return ();
-    }
-
-    match config.opa_url {
-        Some(url) => {
-            server::new(move || {
-                let jsonapi_app = App::with_state(AppState {
-                    modelstore: modelstore.clone(),
-                })
-                .prefix("jsonapi")
-                .middleware(middleware::Logger::default())
-                .middleware(Verifier::build(url.to_string()))
-                .scope("/model", |model_scope| {
-                    model_scope
-                        .resource("", |r| {
-                            r.method(Method::GET).f(get_models);
-                            r.method(Method::POST).with(create_model);
-                        })
-                        .resource("/{model_id}", |r| {
-                            r.method(Method::GET).f(get_model);
-                            r.method(Method::PUT).f(update_model);
-                            // r.method(Method::DELETE).f(get_model)
-                        })
-                    /*
-                    .nested("/{model_id}/pages", |page_scope| {
-                        page_scope
-                        .resource("", |r| {
-                            r.method(Method::GET).f(get_pages);
-                            // r.method(Method::POST).f(get_page);
-                        })
-                        .resource("/{page_id}", |r| {
-                        r.method(Method::GET).f(get_page);
-                        // r.method(Method::PUT).with(get_page);
-                        // r.method(Method::DELETE).with(get_page)
-                        })
-                    })
-                    */
-                    .nested("/{model_id}/xflows", |xflow_scope| {
-                        xflow_scope
-                        .resource("", |r| {
-                            // r.method(Method::GET).f(get_xflows);
-                            // r.method(Method::POST).f(create_page);
-                        })
-                        // .resource("/{xflow_id}", |r| {
-                        // r.method(Method::GET).f(get_xflow);
-                        // r.method(Method::PUT).with(get_page);
-                        // r.method(Method::DELETE).with(get_page)
-                        // })
-                    })
-                })
-                .resource("/", |r| r.f(jsonapi_index))
-                .resource("", |r| r.f(jsonapi_index))
-                .default_resource(|r| {
-                    r.method(Method::GET).f(http_not_found);
-                    r.route()
-                        .filter(pred::Not(pred::Get()))
-                        .f(|_req| HttpResponse::MethodNotAllowed());
-                });
-
-                vec![jsonapi_app]
-            })
-            .bind("0.0.0.0:8080")
-                .unwrap()
-                .start();
-        }
-        None => {
-            server::new(move || {
-                let jsonapi_app = App::with_state(AppState {
-                    modelstore: modelstore.clone(),
-                })
-                .prefix("jsonapi")
-                .middleware(middleware::Logger::default())
-                .scope("/model", |model_scope| {
-                    model_scope
-                        .resource("", |r| {
-                            r.method(Method::GET).f(get_models);
-                            r.method(Method::POST).with(create_model);
-                        })
-                        .resource("/{model_id}", |r| {
-                            r.method(Method::GET).f(get_model);
-                            r.method(Method::PUT).f(update_model);
-                            // r.method(Method::DELETE).f(get_model)
-                        })
-                })
-                .resource("/", |r| r.f(jsonapi_index))
-                .resource("", |r| r.f(jsonapi_index))
-                .default_resource(|r| {
-                    r.method(Method::GET).f(http_not_found);
-                    r.route()
-                        .filter(pred::Not(pred::Get()))
-                        .f(|_req| HttpResponse::MethodNotAllowed());
-                });
-
-                vec![jsonapi_app]
-            })
-            .bind("0.0.0.0:8080")
-                .unwrap()
-                .start();
-        }
+        return Err("enable_opa is set but no URL for OPA is configured".to_owned());
+    }
+    if let Some(ref cors) = config.cors {
+        check_cors(cors).map_err(|err| format!("invalid CORS configuration, {}", err))?;
+    }
+
+    let jwt_auth = match config.auth {
+        Some(ref auth) => Some(JwtAuth::new(auth).map_err(|err| format!("invalid JWT configuration, {}", err))?),
+        None => None,
     };
+    if jwt_auth.is_some() && config.admin_token.is_some() {
+        error!("serve: the admin token is ignored with JWT authentication, use admin roles instead");
+    }
+
+    server::new(move || {
+        vec![jsonapi_app(
+            AppState {
+                store: store.clone(),
+                config: config.clone(),
+                feed: feed.clone(),
+            },
+            jwt_auth.clone(),
+        )]
+    })
+    .bind("0.0.0.0:8080")
+    .map_err(|err| format!("unable to bind 0.0.0.0:8080, {}", err))?
+    .start();
 
     println!("Started http server: 0.0.0.0:8080");
     let _ = sys.run();
+    Ok(())
+}
+
+type Routes = fn(&mut Resource<AppState>);
+
+/// The model resources, with the routes of each
+static MODEL_RESOURCES: &'static [(&'static str, Routes)] = &[
+    ("/model", models_routes),
+    ("/model/{model_id}", model_routes),
+    ("/model/{model_id}/changes", changes_routes),
+    ("/model/{model_id}/revisions", revisions_routes),
+    ("/model/{model_id}/revisions/{revision}", revision_routes),
+    ("/model/{model_id}/{relationship}", related_routes),
+];
+
+fn models_routes(r: &mut Resource<AppState>) {
+    r.method(Method::GET).f(get_models);
+    r.method(Method::POST).f(create_model);
+}
+
+fn model_routes(r: &mut Resource<AppState>) {
+    r.method(Method::GET).f(get_model);
+    r.method(Method::PUT).f(update_model);
+    r.method(Method::PATCH).f(patch_model);
+    r.method(Method::DELETE).f(delete_model);
+}
+
+fn changes_routes(r: &mut Resource<AppState>) {
+    r.method(Method::GET).f(get_changes);
+}
+
+fn revisions_routes(r: &mut Resource<AppState>) {
+    r.method(Method::GET).f(get_revisions);
+}
+
+fn revision_routes(r: &mut Resource<AppState>) {
+    r.method(Method::GET).f(get_revision);
+}
+
+fn related_routes(r: &mut Resource<AppState>) {
+    r.method(Method::GET).f(get_related);
+}
+
+/// The jsonapi application, with the middleware composed from the server
+/// configuration
+fn jsonapi_app(state: AppState, jwt_auth: Option<JwtAuth>) -> App<AppState> {
+    let config = state.config.clone();
+
+    let mut app = App::with_state(state)
+        .prefix("jsonapi")
+        .middleware(middleware::Logger::new(LOG_FORMAT));
+    // CORS goes around the token and policy checks, so that it answers
+    // preflight requests and its headers are also on their 401 and 403
+    if let Some(ref cors) = config.cors {
+        let mut builder = Cors::build();
+        cors_options(&mut builder, cors);
+        app = app.middleware(builder.finish());
+    }
+    if let Some(jwt_auth) = jwt_auth {
+        app = app.middleware(SkipPreflight(jwt_auth));
+    }
+    if let Some(ref url) = config.opa_url {
+        app = app.middleware(SkipPreflight(Verifier::build(url.to_string())));
+    }
+
+    MODEL_RESOURCES
+        .iter()
+        .fold(app, |app, &(path, routes)| app.resource(path, routes))
+        .resource("/", |r| r.f(jsonapi_index))
+        .resource("", |r| r.f(jsonapi_index))
+        .default_resource(|r| {
+            r.method(Method::GET).f(http_not_found);
+            r.route()
+                .filter(pred::Not(pred::Get()))
+                .f(http_method_not_allowed);
+        })
+}
+
+/// Lets preflight requests past a middleware without a token or a policy
+/// decision, and turns its errors into responses, so that the CORS middleware
+/// around it adds its headers to them
+struct SkipPreflight<M>(M);
+
+fn is_preflight<S>(req: &HttpRequest<S>) -> bool {
+    *req.method() == Method::OPTIONS
+}
+
+impl<S: 'static, M: Middleware<S>> Middleware<S> for SkipPreflight<M> {
+    fn start(&self, req: &HttpRequest<S>) -> error::Result<Started> {
+        if is_preflight(req) {
+            Ok(Started::Done)
+        } else {
+            self.0.start(req).or_else(|err| Ok(Started::Response(err.into())))
+        }
+    }
+
+    fn response(&self, req: &HttpRequest<S>, resp: HttpResponse) -> error::Result<Response> {
+        if is_preflight(req) {
+            Ok(Response::Done(resp))
+        } else {
+            self.0.response(req, resp)
+        }
+    }
+
+    fn finish(&self, req: &HttpRequest<S>, resp: &HttpResponse) -> Finished {
+        if is_preflight(req) {
+            Finished::Done
+        } else {
+            self.0.finish(req, resp)
+        }
+    }
+}
+
+/// Check the CORS configuration before starting, as the middleware panics on
+/// invalid origins, methods or headers
+fn check_cors(cors: &CorsConfig) -> Result<(), String> {
+    for origin in cors.allowed_origins.iter().filter(|origin| *origin != "*") {
+        origin
+            .parse::<Uri>()
+            .map_err(|_| format!("'{}' is not a valid origin", origin))?;
+    }
+    for method in &cors.allowed_methods {
+        Method::from_bytes(method.as_bytes()).map_err(|_| format!("'{}' is not a valid method", method))?;
+    }
+    for name in &cors.allowed_headers {
+        HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("'{}' is not a valid header", name))?;
+    }
+    Ok(())
+}
+
+fn cors_options<S: 'static>(builder: &mut CorsBuilder<S>, cors: &CorsConfig) {
+    if !cors.allowed_origins.iter().any(|origin| origin == "*") {
+        for origin in &cors.allowed_origins {
+            builder.allowed_origin(origin);
+        }
+    }
+    if !cors.allowed_methods.is_empty() {
+        builder.allowed_methods(cors.allowed_methods.iter().map(|method| method.as_str()));
+    }
+    if !cors.allowed_headers.is_empty() {
+        builder.allowed_headers(cors.allowed_headers.iter().map(|name| name.as_str()));
+    }
+    if let Some(max_age) = cors.max_age {
+        builder.max_age(max_age);
+    }
+    // Editors need the ETag to send it back in If-Match
+    builder.expose_headers(vec![header::ETAG, header::LOCATION]);
 }
 
 fn jsonapi_index(_req: &HttpRequest<AppState>) -> HttpResponse {
@@ -171,8 +287,76 @@ fn jsonapi_index(_req: &HttpRequest<AppState>) -> HttpResponse {
         .finish()
 }
 
-fn http_not_found(_req: &HttpRequest<AppState>) -> HttpResponse {
-    HttpResponse::build(StatusCode::NOT_FOUND).finish()
+fn etag_of(revision: &str) -> String {
+    format!("\"{}\"", revision)
+}
+
+/// Tag a response with the revision of the model it holds. The tag is the
+/// same for every format, so caches must also key on the Accept header.
+fn with_etag(mut resp: HttpResponse, revision: &str) -> HttpResponse {
+    if let Ok(etag) = HeaderValue::from_str(&etag_of(revision)) {
+        resp.headers_mut().insert(header::ETAG, etag);
+    }
+    resp.headers_mut().insert(header::VARY, HeaderValue::from_static("Accept"));
+    resp
+}
+
+/// The comma separated values of a header such as If-Match
+fn header_values<S>(req: &HttpRequest<S>, name: &str) -> Option<Vec<String>> {
+    req.headers()
+        .get(name)
+        .and_then(|val| val.to_str().ok())
+        .map(|val| val.split(',').map(|tag| tag.trim().to_owned()).collect())
+}
+
+/// The revisions in an If-Match header, without their quotes. Weak tags never
+/// match, and `*` or no header at all means any revision.
+fn if_match<S>(req: &HttpRequest<S>) -> Option<Vec<String>> {
+    header_values(req, "IF-MATCH")
+        .filter(|tags| !tags.iter().any(|tag| tag == "*"))
+        .map(|tags| tags.iter().map(|tag| tag.trim_matches('"').to_owned()).collect())
+}
+
+/// Who is making a change and why, from the bearer token or the From header
+/// and the X-Change-Message header
+fn change_info(req: &HttpRequest<AppState>, default_message: &str) -> ChangeInfo {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|val| val.to_str().ok())
+            .map(|val| val.to_owned())
+    };
+    let user = req.extensions().get::<Identity>().map(|identity| identity.user.clone());
+    ChangeInfo {
+        author: user
+            .or_else(|| header("FROM"))
+            .unwrap_or_else(|| "anonymous".to_owned()),
+        message: header("X-CHANGE-MESSAGE").unwrap_or_else(|| default_message.to_owned()),
+    }
+}
+
+/// Whether the caller may use privileged options such as `?force=true`
+fn is_privileged(req: &HttpRequest<AppState>) -> bool {
+    if let Some(ref config) = req.state().config.auth {
+        return req
+            .extensions()
+            .get::<Identity>()
+            .map_or(false, |identity| auth::is_admin(config, identity));
+    }
+    match req.state().config.admin_token {
+        Some(ref token) => auth::bearer_token(req).map_or(false, |val| {
+            auth::constant_time_eq(val.as_bytes(), token.as_bytes())
+        }),
+        None => false,
+    }
+}
+
+fn http_not_found(req: &HttpRequest<AppState>) -> HttpResponse {
+    ApiError::not_found(&format!("No resource at '{}'", req.path())).response()
+}
+
+fn http_method_not_allowed(req: &HttpRequest<AppState>) -> HttpResponse {
+    ApiError::method_not_allowed(&format!("{} is not allowed on '{}'", req.method(), req.path())).response()
 }
 fn http_ok_doc<T>(doc: &T) -> HttpResponse where T : JsonApiModel {
     HttpResponse::build(StatusCode::OK)
@@ -180,52 +364,339 @@ fn http_ok_doc<T>(doc: &T) -> HttpResponse where T : JsonApiModel {
         .body(format!("{}", serde_json::to_string(&doc.to_jsonapi_document()).unwrap()))
 }
 
-fn http_bad_request(msg : &str) -> HttpResponse {
-    HttpResponse::build(StatusCode::BAD_REQUEST)
-        .content_type(CONTENT_TYPE_JSON)
-        .body(format!("{:?}", msg))
+fn http_ok_jsonapi(doc: &serde_json::Value) -> HttpResponse {
+    HttpResponse::build(StatusCode::OK)
+        .content_type(CONTENT_TYPE_JSONAPI)
+        .body(doc.to_string())
+}
+
+fn http_ok_collection(doc: Result<serde_json::Value, ApiError>) -> HttpResponse {
+    match doc {
+        Ok(doc) => http_ok_jsonapi(&doc),
+        Err(err) => err.response(),
+    }
+}
+
+fn http_ok_model(model: &GxModel, format: &Format) -> HttpResponse {
+    match formats::encode(model, format) {
+        Ok(body) => HttpResponse::build(StatusCode::OK)
+            .content_type(format.mime_type())
+            .body(body),
+        Err(err) => ApiError::load_failure(&err).response(),
+    }
+}
+
+/// A model as GET serves it, the JSON:API document or the format asked for in
+/// the Accept header, with its ETag
+fn http_ok_model_document(req: &HttpRequest<AppState>, stored: &StoredModel, query: &Query) -> HttpResponse {
+    let resp = match header_format(req, "ACCEPT") {
+        None | Some(Format::JSON) => http_ok_jsonapi(&resource::model_document(&stored.model, query, req.path())),
+        Some(format) => http_ok_model(&stored.model, &format),
+    };
+    with_etag(resp, &stored.revision)
+}
+
+/// The first format in a header such as Accept or Content-Type that a model
+/// can be serialized to
+fn header_format(req: &HttpRequest<AppState>, header: &str) -> Option<Format> {
+    req.headers()
+        .get(header)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.split(',').filter_map(Format::from_mime).next())
+}
+
+/// Send a message to the model store actor, and build the response from the
+/// result once the store has handled it
+fn ask<M, F>(req: &HttpRequest<AppState>, msg: M, respond: F) -> FutureResponse<HttpResponse>
+where
+    M: Message + Send + 'static,
+    M::Result: Send,
+    FileSystemModelStore: Handler<M>,
+    SyncContext<FileSystemModelStore>: ToEnvelope<FileSystemModelStore, M>,
+    F: FnOnce(&HttpRequest<AppState>, M::Result) -> HttpResponse + 'static,
+{
+    let req = req.clone();
+    req.state()
+        .store
+        .send(msg)
+        .then(move |res| -> Result<HttpResponse, error::Error> {
+            match res {
+                Ok(result) => Ok(respond(&req, result)),
+                Err(err) => Ok(ApiError::unavailable(&format!("Model store unavailable: {}", err)).response()),
+            }
+        })
+        .responder()
+}
+
+fn respond_now(resp: HttpResponse) -> FutureResponse<HttpResponse> {
+    Box::new(future::ok(resp))
 }
 
 //
 // Models
 
-fn get_models(req: &HttpRequest<AppState>) -> HttpResponse {
-    match &req.state().modelstore.get(&"") {
-        Ok(res) => http_ok_doc(res),
-        Err(_) => http_not_found(&req)
-    }
+fn get_models(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let query = match Query::from_params(&req.query()) {
+        Ok(query) => query,
+        Err(err) => return respond_now(err.response()),
+    };
+    ask(req, ModelStoreList, move |req, res| match res {
+        Ok(models) => http_ok_collection(resource::model_collection(&models, &query, req.path())),
+        Err(err) => ApiError::load_failure(&err).response(),
+    })
 }
 
-fn get_model(req: &HttpRequest<AppState>) -> HttpResponse {
-    let model_id = &req.match_info()["model_id"];
-    match &req.state().modelstore.get(&model_id) {
-        Ok(res) => http_ok_doc(res),
-        Err(_) => http_not_found(&req)
-    }
+fn get_model(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let model_id = req.match_info()["model_id"].to_owned();
+    let query = match Query::from_params(&req.query()) {
+        Ok(query) => query,
+        Err(err) => return respond_now(err.response()),
+    };
+    ask(req, ModelStoreGet { id: model_id }, move |req, res| match res {
+        Ok(ref res) => {
+            let etag = etag_of(&res.revision);
+            if header_values(&req, "IF-NONE-MATCH").map_or(false, |tags| tags.contains(&etag)) {
+                return HttpResponse::NotModified()
+                    .header(header::ETAG, etag)
+                    .header(header::VARY, "Accept")
+                    .finish();
+            }
+            http_ok_model_document(&req, res, &query)
+        }
+        Err(ref err) => ApiError::load_failure(err).response(),
+    })
+}
+
+fn delete_model(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let msg = ModelStoreDelete {
+        id: req.match_info()["model_id"].to_owned(),
+        expected: if_match(&req),
+    };
+    ask(req, msg, |_req, res| match res {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(ApiError::from_store_error(&err)),
+    })
+}
+
+/// Server-sent events for every change to a model, through the API or on disk
+fn get_changes(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let model_id = req.match_info()["model_id"].to_owned();
+    ask(req, ModelStoreGet { id: model_id.clone() }, move |req, res| match res {
+        Ok(ref stored) if stored.model.id.to_string() == model_id => {
+            let events = req
+                .state()
+                .feed
+                .subscribe(&model_id)
+                .map_err(|_| error::ErrorInternalServerError("Change feed closed"));
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .header("CACHE-CONTROL", "no-cache")
+                .streaming(events)
+        }
+        Ok(_) => ApiError::not_found(&format!("No model '{}'", model_id)).response(),
+        Err(err) => ApiError::load_failure(&err).response(),
+    })
+}
+
+fn revision_resource(info: &RevisionInfo, path: &str) -> serde_json::Value {
+    json!({
+        "type": "revision",
+        "id": info.revision,
+        "attributes": {
+            "author": info.author,
+            "timestamp": info.timestamp,
+            "message": info.message,
+        },
+        "links": { "self": path },
+    })
+}
+
+fn get_revisions(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let model_id = req.match_info()["model_id"].to_owned();
+    let query = match Query::from_params(&req.query()) {
+        Ok(query) => query,
+        Err(err) => return respond_now(err.response()),
+    };
+    ask(req, ModelStoreRevisions { id: model_id }, move |req, res| match res {
+        Ok(revisions) => {
+            let resources = revisions
+                .iter()
+                .map(|info| revision_resource(info, &format!("{}/{}", req.path(), info.revision)))
+                .collect();
+            http_ok_collection(resource::collection(resources, &query, req.path(), |_| Vec::new()))
+        }
+        Err(err) => ApiError::load_failure(&err).response(),
+    })
+}
+
+/// A saved revision of a model, with the model as it was then
+fn get_revision(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let msg = ModelStoreGetRevision {
+        id: req.match_info()["model_id"].to_owned(),
+        revision: req.match_info()["revision"].to_owned(),
+    };
+    ask(req, msg, move |req, res| match res {
+        Ok((info, model)) => {
+            let mut resource = revision_resource(&info, req.path());
+            resource["attributes"]["model"] = diff::model_to_value(&model);
+            with_etag(http_ok_jsonapi(&json!({ "data": resource })), &info.revision)
+        }
+        Err(err) => error_response(ApiError::from_store_error(&err)),
+    })
+}
+
+fn get_related(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let model_id = req.match_info()["model_id"].to_owned();
+    let query = match Query::from_params(&req.query()) {
+        Ok(query) => query,
+        Err(err) => return respond_now(err.response()),
+    };
+    ask(req, ModelStoreGet { id: model_id }, move |req, res| {
+        let model = match res {
+            Ok(stored) => stored.model,
+            Err(err) => return ApiError::load_failure(&err).response(),
+        };
+        let relationship = &req.match_info()["relationship"];
+        match resource::related(&diff::model_to_value(&model), relationship) {
+            Some(resources) => http_ok_collection(resource::collection(resources, &query, req.path(), |_| Vec::new())),
+            None => http_not_found(&req),
+        }
+    })
 }
 
-fn create_model(doc: Json<JsonApiDocument>) -> HttpResponse {
-    let obj = &doc.into_inner();
-    let model = ModelDocument::from_jsonapi_document(&obj).unwrap();
-    http_ok_doc(&model)
+fn create_model(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    req.body()
+        .then(|body| -> Result<HttpResponse, error::Error> {
+            let bytes = match body {
+                Ok(bytes) => bytes,
+                Err(err) => return Ok(ApiError::from_payload_error(&err).response()),
+            };
+            let doc: JsonApiDocument = match serde_json::from_slice(&bytes) {
+                Ok(doc) => doc,
+                Err(err) => return Ok(ApiError::bad_request(&format!("Invalid JSON:API document: {}", err)).response()),
+            };
+            Ok(match ModelDocument::from_jsonapi_document(&doc) {
+                Ok(model) => http_ok_doc(&model),
+                Err(err) => ApiError::bad_request(&format!("{:?}", err)).response(),
+            })
+        })
+        .responder()
+}
+
+/// The response to a successful update, the model as GET serves it. The store
+/// announces the change on the feed from its own thread.
+fn http_updated(req: &HttpRequest<AppState>, res: Result<StoredModel, StoreError>, query: &Query) -> HttpResponse {
+    match res {
+        Ok(ref stored) => http_ok_model_document(req, stored, query),
+        Err(err) => error_response(ApiError::from_store_error(&err)),
+    }
 }
 
 fn update_model(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let query = match Query::from_params(&req.query()) {
+        Ok(query) => query,
+        Err(err) => return respond_now(err.response()),
+    };
+
+    let req = req.clone();
+    req.body()
+        .then(move |body| -> FutureResponse<HttpResponse> {
+            let bytes = match body {
+                Ok(bytes) => bytes,
+                Err(err) => return respond_now(ApiError::from_payload_error(&err).response()),
+            };
+            let model = match header_format(&req, "CONTENT-TYPE") {
+                None | Some(Format::JSON) => match str::from_utf8(&bytes) {
+                    Ok(body) => GxModel::from_json(body)
+                        .map_err(|err| ModelLoadError::InputError(format!("{:?}", err))),
+                    Err(_) => return respond_now(ApiError::bad_request("Request body is not valid UTF-8").response()),
+                },
+                Some(format) => formats::decode(&bytes, Some(&format)),
+            };
+            let model = match model {
+                Ok(model) => model,
+                Err(err) => return respond_now(ApiError::from_load_error(&err).response()),
+            };
+
+            let force = req.query().get("force").map_or(false, |val| val == "true");
+            if force && !is_privileged(&req) {
+                return respond_now(ApiError::forbidden("Only privileged callers can force an update").response());
+            }
+
+            let errors = validate(&model);
+            if !errors.is_empty() {
+                if !force {
+                    return respond_now(error_response(ApiError::from_store_error(&StoreError::Invalid(errors))));
+                }
+                info!(
+                    "update_model: forced update of model '{}' with {} validation errors",
+                    model.id,
+                    errors.len()
+                );
+            }
+
+            let msg = ModelStoreUpdate {
+                json: model.to_json(),
+                expected: if_match(&req),
+                change: change_info(&req, "Updated model"),
+            };
+            ask(&req, msg, move |req, res| http_updated(req, res, &query))
+        })
+        .responder()
+}
+
+/// Apply an RFC 6902 JSON Patch or an RFC 7396 merge patch, depending on the
+/// Content-Type, to the JSON of a model, and respond with the model as GET
+/// serves it
+fn patch_model(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let query = match Query::from_params(&req.query()) {
+        Ok(query) => query,
+        Err(err) => return respond_now(err.response()),
+    };
 
     let req = req.clone();
     req.body()
-        .from_err()
-        .and_then(move |bytes: Bytes| {
-            match str::from_utf8(&bytes) {
-                Ok(body) => {
-                    // println!("==== BODY ==== {:?}", body);
-                    match &req.state().modelstore.update(&body) {
-                        Ok(res) => Ok(http_ok_doc(res)),
-                        Err(err) => Ok(http_bad_request(&format!("{:?}", err))),
-                    }
+        .then(move |body| -> FutureResponse<HttpResponse> {
+            let bytes = match body {
+                Ok(bytes) => bytes,
+                Err(err) => return respond_now(ApiError::from_payload_error(&err).response()),
+            };
+            let content_type = req
+                .headers()
+                .get("CONTENT-TYPE")
+                .and_then(|val| val.to_str().ok())
+                .and_then(|val| val.split(';').next())
+                .map_or(String::new(), |val| val.trim().to_owned());
+            let patch = match content_type.as_str() {
+                "application/json-patch+json" => serde_json::from_slice(&bytes).map(ModelPatch::Json),
+                "application/merge-patch+json" => serde_json::from_slice(&bytes).map(ModelPatch::Merge),
+                _ => {
+                    return respond_now(
+                        ApiError::unsupported_media_type(
+                            "PATCH takes application/json-patch+json or application/merge-patch+json",
+                        )
+                        .response(),
+                    )
                 }
-                Err(_) => Ok(http_bad_request(&"Invalid JSON"))
+            };
+            let patch = match patch {
+                Ok(patch) => patch,
+                Err(err) => return respond_now(ApiError::bad_request(&format!("Invalid patch: {}", err)).response()),
+            };
+
+            let force = req.query().get("force").map_or(false, |val| val == "true");
+            if force && !is_privileged(&req) {
+                return respond_now(ApiError::forbidden("Only privileged callers can force an update").response());
             }
+
+            let msg = ModelStorePatch {
+                id: req.match_info()["model_id"].to_owned(),
+                patch: patch,
+                expected: if_match(&req),
+                force: force,
+                change: change_info(&req, "Patched model"),
+            };
+            ask(&req, msg, move |req, res| http_updated(req, res, &query))
         })
         .responder()
 }
@@ -288,3 +759,72 @@ fn get_xflow(req:&HttpRequest<AppState>) -> impl Responder {
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use fixtures;
+    use modelstore::model_executor::revision_of;
+
+    fn cors(origins: &[&str], methods: &[&str], headers: &[&str]) -> CorsConfig {
+        let strings = |values: &[&str]| values.iter().map(|val| val.to_string()).collect();
+        CorsConfig {
+            allowed_origins: strings(origins),
+            allowed_methods: strings(methods),
+            allowed_headers: strings(headers),
+            max_age: None,
+        }
+    }
+
+    #[test]
+    fn valid_cors_configurations_are_accepted() {
+        assert!(check_cors(&CorsConfig::default()).is_ok());
+        assert!(check_cors(&cors(&["*"], &[], &[])).is_ok());
+        assert!(check_cors(&cors(
+            &["https://editor.example.com"],
+            &["GET", "PATCH"],
+            &["If-Match", "Authorization"]
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn invalid_cors_configurations_are_rejected() {
+        assert!(check_cors(&cors(&["not an origin"], &[], &[])).is_err());
+        assert!(check_cors(&cors(&[], &["GET POST"], &[])).is_err());
+        assert!(check_cors(&cors(&[], &[], &["If Match"])).is_err());
+    }
+
+    fn if_match_of(value: &str) -> Option<Vec<String>> {
+        if_match(&TestRequest::with_header("If-Match", value).finish())
+    }
+
+    #[test]
+    fn if_match_lists_the_expected_revisions() {
+        assert_eq!(if_match(&TestRequest::default().finish()), None);
+        assert_eq!(if_match_of("*"), None);
+        assert_eq!(if_match_of("\"3f2a\""), Some(vec!["3f2a".to_owned()]));
+        assert_eq!(
+            if_match_of("\"3f2a\", \"9c1e\""),
+            Some(vec!["3f2a".to_owned(), "9c1e".to_owned()])
+        );
+    }
+
+    #[test]
+    fn weak_tags_never_match() {
+        let revision = revision_of(&fixtures::model());
+        let weak = if_match_of(&format!("W/{}", etag_of(&revision))).unwrap();
+        assert!(!weak.contains(&revision));
+
+        let strong = if_match_of(&etag_of(&revision)).unwrap();
+        assert!(strong.contains(&revision));
+    }
+
+    #[test]
+    fn tagged_responses_vary_by_format() {
+        let resp = with_etag(HttpResponse::Ok().finish(), "3f2a");
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"3f2a\"");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+    }
+}
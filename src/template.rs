@@ -0,0 +1,246 @@
+use gears::structure::common::{DocumentFileSystemLoadable, ModelLoadError};
+use gears::structure::gxmodel::GxModel;
+use serde_json::{self, Value};
+use serde_yaml;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub static TEMPLATE_PATH_VAR: &'static str = "GEARS_TEMPLATE_PATH";
+
+pub static PLACEHOLDER_NAME: &'static str = "{{project_name}}";
+pub static PLACEHOLDER_LOCALE: &'static str = "{{locale}}";
+
+/// Templates shipped with gears-cli, by name
+static BUILTIN_TEMPLATES: &'static [(&'static str, &'static str)] = &[("crud", include_str!("../templates/crud.json"))];
+
+/// Where a template comes from
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    Path(PathBuf),
+    Builtin(&'static str),
+}
+
+fn template_dir() -> Option<PathBuf> {
+    match env::var(TEMPLATE_PATH_VAR) {
+        Ok(dir) => Some(PathBuf::from(dir)),
+        Err(_) => env::var("HOME")
+            .ok()
+            .map(|home| Path::new(&home).join(".gears").join("templates")),
+    }
+}
+
+/// Find a template by name in the directory set in `GEARS_TEMPLATE_PATH`,
+/// defaulting to `~/.gears/templates`, then among the built-in templates, and
+/// only then as a path. A template with a path separator, such as `./crud`,
+/// is always a path.
+pub fn resolve(template: &str) -> Option<Source> {
+    resolve_in(template, template_dir())
+}
+
+fn resolve_in(template: &str, template_dir: Option<PathBuf>) -> Option<Source> {
+    let path = PathBuf::from(template);
+    if template.contains('/') || template.contains('\\') {
+        return if path.exists() { Some(Source::Path(path)) } else { None };
+    }
+
+    let named = template_dir.and_then(|dir| {
+        ["", ".json", ".yaml", ".yml"]
+            .iter()
+            .map(|ext| dir.join(format!("{}{}", template, ext)))
+            .find(|path| path.exists())
+    });
+    if let Some(named) = named {
+        return Some(Source::Path(named));
+    }
+    if let Some(&(_, source)) = BUILTIN_TEMPLATES.iter().find(|&&(name, _)| name == template) {
+        return Some(Source::Builtin(source));
+    }
+    if path.exists() {
+        return Some(Source::Path(path));
+    }
+    None
+}
+
+/// Load a template model from a model directory or an exported model file,
+/// substituting the project name and locale placeholders and giving the
+/// result new document ids
+pub fn load(template: &str, name: &str, locale: &str) -> Result<GxModel, ModelLoadError> {
+    match resolve(template) {
+        Some(source) => load_from(&source, name, locale),
+        None => Err(ModelLoadError::InputError(format!(
+            "Template '{}' not found",
+            template
+        ))),
+    }
+}
+
+fn load_from(source: &Source, name: &str, locale: &str) -> Result<GxModel, ModelLoadError> {
+    let mut doc = match *source {
+        Source::Path(ref path) => {
+            info!("template: loading '{}'", path.display());
+            parse_template(&read_template(path)?, is_yaml(path))?
+        }
+        Source::Builtin(source) => parse_template(source, false)?,
+    };
+
+    substitute(&mut doc, name, locale);
+    renew_ids(&mut doc);
+    GxModel::from_json(&doc.to_string()).map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))
+}
+
+fn parse_template(source: &str, yaml: bool) -> Result<Value, ModelLoadError> {
+    if yaml {
+        serde_yaml::from_str(source).map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))
+    } else {
+        serde_json::from_str(source).map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))
+    }
+}
+
+/// Replace the placeholders in the strings of a parsed template, so that a
+/// project name with quotes or newlines stays a plain string
+fn substitute(value: &mut Value, name: &str, locale: &str) {
+    match *value {
+        Value::String(ref mut text) => {
+            if text.contains(PLACEHOLDER_NAME) || text.contains(PLACEHOLDER_LOCALE) {
+                *text = text
+                    .replace(PLACEHOLDER_NAME, name)
+                    .replace(PLACEHOLDER_LOCALE, locale);
+            }
+        }
+        Value::Array(ref mut items) => {
+            for item in items.iter_mut() {
+                substitute(item, name, locale);
+            }
+        }
+        Value::Object(ref mut members) => {
+            for (_, member) in members.iter_mut() {
+                substitute(member, name, locale);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn renew_id(doc: &mut Value) {
+    doc["id"] = Value::String(Uuid::new_v4().to_string());
+}
+
+/// Give the model and each of its documents a new id, so that projects made
+/// from the same template do not share document ids
+fn renew_ids(doc: &mut Value) {
+    renew_id(doc);
+    for pointer in &["/body/config", "/body/domain"] {
+        if let Some(document) = doc.pointer_mut(pointer) {
+            renew_id(document);
+        }
+    }
+    for pointer in &["/body/xflows", "/body/pages", "/body/translations"] {
+        if let Some(&mut Value::Array(ref mut documents)) = doc.pointer_mut(pointer) {
+            for document in documents.iter_mut() {
+                renew_id(document);
+            }
+        }
+    }
+}
+
+fn read_template(path: &Path) -> Result<String, ModelLoadError> {
+    if path.is_dir() {
+        let path = path.to_string_lossy();
+        return GxModel::load_from_filesystem(&path).map(|model| model.to_json());
+    }
+
+    let mut buffer = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut buffer))
+        .map_err(|err| ModelLoadError::InputError(format!("{:?}", err)))?;
+    Ok(buffer)
+}
+
+fn is_yaml(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diff::model_to_value;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn placeholders_are_replaced_inside_strings() {
+        let mut doc = json!({
+            "name": "{{project_name}}",
+            "body": {"translations": [{"body": {"locale": "{{locale}}"}}]},
+            "version": 1,
+        });
+        substitute(&mut doc, "shop \"north\"\n\\", "nl_NL");
+
+        assert_eq!(doc["name"], json!("shop \"north\"\n\\"));
+        assert_eq!(doc["body"]["translations"][0]["body"]["locale"], json!("nl_NL"));
+        assert_eq!(doc["version"], json!(1));
+    }
+
+    #[test]
+    fn named_templates_come_before_paths() {
+        let dir = env::temp_dir().join(format!("gears-cli-templates-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("crud.json"), "{}").unwrap();
+
+        assert_eq!(resolve_in("crud", Some(dir.clone())), Some(Source::Path(dir.join("crud.json"))));
+        match resolve_in("crud", None) {
+            Some(Source::Builtin(_)) => {}
+            other => panic!("expected the built-in template, got {:?}", other),
+        }
+        assert_eq!(resolve_in("./no-such-template", Some(dir.clone())), None);
+        assert_eq!(resolve_in("no-such-template", Some(dir.clone())), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_crud_template_loads() {
+        let source = resolve_in("crud", None).unwrap();
+        let model = load_from(&source, "shop \"north\"", "nl_NL").unwrap();
+        let doc = model_to_value(&model);
+
+        assert!(!doc.to_string().contains("{{"));
+        assert!(doc.to_string().contains("shop \\\"north\\\""));
+    }
+
+    fn document_ids(doc: &Value) -> Vec<String> {
+        let mut ids = vec![doc["id"].to_string()];
+        ids.push(doc["body"]["config"]["id"].to_string());
+        ids.push(doc["body"]["domain"]["id"].to_string());
+        for kind in &["xflows", "pages", "translations"] {
+            for document in doc["body"][kind].as_array().unwrap() {
+                ids.push(document["id"].to_string());
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn every_document_gets_a_new_id() {
+        let source = resolve_in("crud", None).unwrap();
+        let first = model_to_value(&load_from(&source, "shop", "en_US").unwrap());
+        let second = model_to_value(&load_from(&source, "shop", "en_US").unwrap());
+        let template: Value = serde_json::from_str(include_str!("../templates/crud.json")).unwrap();
+
+        assert!(!first["body"]["domain"]["body"]["entities"].as_array().unwrap().is_empty());
+        assert!(!first["body"]["xflows"].as_array().unwrap().is_empty());
+        assert!(!first["body"]["pages"].as_array().unwrap().is_empty());
+        for (id, (other, original)) in document_ids(&first)
+            .iter()
+            .zip(document_ids(&second).iter().zip(document_ids(&template).iter()))
+        {
+            assert_ne!(id, other);
+            assert_ne!(id, original);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Write `data` to `filename` atomically, by writing a temporary file next to
+/// it and renaming that over the original. With `backup` set, an existing
+/// file is first copied to `<filename>.bk`.
+pub fn write_file<D: AsRef<[u8]>>(filename: &str, data: D, backup: bool) -> io::Result<()> {
+    write_file_with(filename, backup, |file| file.write_all(data.as_ref()))
+}
+
+/// Like `write_file`, with `write` streaming the contents into the temporary
+/// file through a buffer
+pub fn write_file_with<F>(filename: &str, backup: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let path = Path::new(filename);
+    let tmp_path = tmp_path_for(path);
+
+    // The temporary file is new, so no other writer or user file is touched
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(&tmp_path) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            error!("couldn't write to {}: {}", path.display(), err);
+            return Err(err);
+        }
+    };
+    let res = write(&mut file)
+        .and_then(|_| file.flush())
+        .and_then(|_| file.get_ref().sync_all());
+    drop(file);
+
+    let res = res.and_then(|_| {
+        if let Ok(metadata) = fs::metadata(&path) {
+            fs::set_permissions(&tmp_path, metadata.permissions())?;
+        }
+        if backup && path.exists() {
+            fs::copy(&path, format!("{}.bk", filename))?;
+        }
+        fs::rename(&tmp_path, &path)
+    });
+
+    match res {
+        Ok(_) => {
+            debug!("successfully wrote to {}", path.display());
+            Ok(())
+        }
+        Err(err) => {
+            error!("couldn't write to {}: {}", path.display(), err);
+            let _ = fs::remove_file(&tmp_path);
+            Err(err)
+        }
+    }
+}
+
+/// A temporary path next to `path` that is unique to this writer
+fn tmp_path_for(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map_or_else(|| "gears".to_owned(), |name| name.to_string_lossy().into_owned());
+    path.with_file_name(format!(".{}.{}", name, tmp_suffix()))
+}
+
+fn tmp_suffix() -> String {
+    format!("{}-{}.tmp", process::id(), TMP_COUNTER.fetch_add(1, Ordering::SeqCst))
+}
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Create a new, empty staging directory in `target`. Staging inside the
+/// target keeps the final renames on one file system, also for `-p .`.
+fn create_staging_dir(target: &Path) -> io::Result<PathBuf> {
+    loop {
+        let staging = target.join(format!(".staging.{}", tmp_suffix()));
+        match fs::create_dir(&staging) {
+            Ok(_) => return Ok(staging),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Run `write` against a new staging directory, then move each file it
+/// produced into `path`. Files in `path` are only ever replaced by complete
+/// files, so an interrupted import or build never leaves truncated documents
+/// behind.
+pub fn write_dir<F, T, E>(path: &str, backup: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&str) -> Result<T, E>,
+    E: Debug,
+{
+    stage_dir(path, write, |staging, target| move_files(staging, target, backup, false, 0))
+}
+
+/// Like `write_dir`, but files that `write` no longer produces are removed
+/// from the subdirectories it wrote to, so documents dropped from a model are
+/// gone from disk. Files at the top of `path`, hidden files and backups are
+/// kept. With `backup` set, removed files are renamed to `<file>.bk`.
+pub fn replace_dir<F, T, E>(path: &str, backup: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&str) -> Result<T, E>,
+    E: Debug,
+{
+    stage_dir(path, write, |staging, target| move_files(staging, target, backup, true, 0))
+}
+
+/// Like `write_dir`, but files that already exist in `path` are left alone.
+/// Returns the files that were created and the files that were skipped.
+pub fn add_missing_files<F, T, E>(path: &str, write: F) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)>
+where
+    F: FnOnce(&str) -> Result<T, E>,
+    E: Debug,
+{
+    stage_dir(path, write, |staging, target| {
+        let mut created = Vec::new();
+        let mut skipped = Vec::new();
+        move_missing_files(staging, target, &mut created, &mut skipped)?;
+        Ok((created, skipped))
+    })
+}
+
+fn stage_dir<F, T, E, M, R>(path: &str, write: F, place: M) -> io::Result<R>
+where
+    F: FnOnce(&str) -> Result<T, E>,
+    E: Debug,
+    M: FnOnce(&Path, &Path) -> io::Result<R>,
+{
+    let target = Path::new(path);
+    fs::create_dir_all(target)?;
+    let staging = create_staging_dir(target)?;
+
+    let res = match write(&staging.to_string_lossy()) {
+        Ok(_) => place(&staging, target),
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", err))),
+    };
+
+    let _ = fs::remove_dir_all(&staging);
+    res
+}
+
+fn move_files(from: &Path, to: &Path, backup: bool, prune: bool, depth: usize) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    let mut written = HashSet::new();
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        written.insert(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            move_files(&entry.path(), &target, backup, prune, depth + 1)?;
+        } else {
+            if backup && target.exists() {
+                fs::copy(&target, backup_path(&target))?;
+            }
+            fs::rename(entry.path(), &target)?;
+        }
+    }
+    if prune && depth > 0 {
+        remove_stale_files(to, &written, backup)?;
+    }
+    Ok(())
+}
+
+fn move_missing_files(from: &Path, to: &Path, created: &mut Vec<PathBuf>, skipped: &mut Vec<PathBuf>) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            move_missing_files(&entry.path(), &target, created, skipped)?;
+        } else if target.exists() {
+            skipped.push(target);
+        } else {
+            fs::rename(entry.path(), &target)?;
+            created.push(target);
+        }
+    }
+    Ok(())
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup_path = path.to_path_buf().into_os_string();
+    backup_path.push(".bk");
+    PathBuf::from(backup_path)
+}
+
+/// Remove the files in `dir` that were not just written
+fn remove_stale_files(dir: &Path, written: &HashSet<OsString>, backup: bool) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let kept = {
+            let name = name.to_string_lossy();
+            name.starts_with('.') || name.ends_with(".bk")
+        };
+        if kept || written.contains(&name) || !entry.file_type()?.is_file() {
+            continue;
+        }
+        if backup {
+            fs::rename(entry.path(), backup_path(&entry.path()))?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+        debug!("removed {}", entry.path().display());
+    }
+    Ok(())
+}
+
+/// The current time as an RFC 3339 UTC timestamp
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("gears-cli-util-{}-{}", name, tmp_suffix()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: &Path) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    fn names(path: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn write_file_replaces_and_backs_up() {
+        let dir = test_dir("write-file");
+        let filename = dir.join("model.json");
+        let filename = filename.to_str().unwrap();
+
+        write_file(filename, "one", false).unwrap();
+        write_file(filename, "two", true).unwrap();
+
+        assert_eq!(read(Path::new(filename)), "two");
+        assert_eq!(read(&dir.join("model.json.bk")), "one");
+        assert_eq!(names(&dir), vec!["model.json", "model.json.bk"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_file_leaves_nothing_behind_on_error() {
+        let dir = test_dir("write-file-error");
+        let filename = dir.join("model.json");
+
+        let res = write_file_with(filename.to_str().unwrap(), false, |_| {
+            Err(io::Error::new(io::ErrorKind::Other, "failed"))
+        });
+
+        assert!(res.is_err());
+        assert!(names(&dir).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_dir_moves_files_and_keeps_other_directories() {
+        let dir = test_dir("write-dir");
+        let target = dir.join("out");
+        fs::create_dir_all(dir.join("out.tmp")).unwrap();
+        write_file(dir.join("out.tmp/keep.txt").to_str().unwrap(), "mine", false).unwrap();
+
+        let res = write_dir(target.to_str().unwrap(), false, |path| {
+            fs::create_dir_all(format!("{}/xflows", path))?;
+            write_file(&format!("{}/xflows/a.json", path), "{}", false)
+        });
+
+        assert!(res.is_ok());
+        assert_eq!(names(&target), vec!["xflows"]);
+        assert_eq!(read(&target.join("xflows/a.json")), "{}");
+        assert_eq!(read(&dir.join("out.tmp/keep.txt")), "mine");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_dir_reports_errors_and_cleans_up() {
+        let dir = test_dir("write-dir-error");
+
+        let res = write_dir(dir.to_str().unwrap(), false, |_| Err::<(), _>("failed"));
+
+        assert!(res.is_err());
+        assert!(names(&dir).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn write_xflows(path: &str, ids: &[&str]) -> io::Result<()> {
+        fs::create_dir_all(format!("{}/xflows", path))?;
+        write_file(&format!("{}/model.json", path), "{}", false)?;
+        for id in ids {
+            write_file(&format!("{}/xflows/{}.json", path, id), "{}", false)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replace_dir_removes_documents_no_longer_written() {
+        let dir = test_dir("replace-dir");
+        let target = dir.to_str().unwrap();
+        write_file(dir.join("README.md").to_str().unwrap(), "readme", false).unwrap();
+
+        write_dir(target, false, |path| write_xflows(path, &["a", "b"])).unwrap();
+        replace_dir(target, false, |path| write_xflows(path, &["a"])).unwrap();
+
+        assert_eq!(names(&dir), vec!["README.md", "model.json", "xflows"]);
+        assert_eq!(names(&dir.join("xflows")), vec!["a.json"]);
+
+        write_dir(target, false, |path| write_xflows(path, &["a", "b"])).unwrap();
+        replace_dir(target, true, |path| write_xflows(path, &["b"])).unwrap();
+        assert_eq!(names(&dir.join("xflows")), vec!["a.json.bk", "b.json", "b.json.bk"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_missing_files_leaves_existing_files_alone() {
+        let dir = test_dir("add-missing");
+        let target = dir.to_str().unwrap();
+        fs::create_dir_all(dir.join("xflows")).unwrap();
+        write_file(dir.join("xflows/a.json").to_str().unwrap(), "mine", false).unwrap();
+
+        let (mut created, skipped) = add_missing_files(target, |path| write_xflows(path, &["a", "b"])).unwrap();
+        created.sort();
+
+        assert_eq!(created, vec![dir.join("model.json"), dir.join("xflows/b.json")]);
+        assert_eq!(skipped, vec![dir.join("xflows/a.json")]);
+        assert_eq!(read(&dir.join("xflows/a.json")), "mine");
+        assert_eq!(names(&dir), vec!["model.json", "xflows"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn timestamp_is_rfc3339() {
+        let ts = timestamp();
+        assert_eq!(ts.len(), 20);
+        assert_eq!(&ts[4..5], "-");
+        assert_eq!(&ts[10..11], "T");
+        assert!(ts.ends_with('Z'));
+    }
+}
@@ -0,0 +1,43 @@
+use std::process::Command;
+
+/// Run the local `git` binary in `path`, returning its stdout
+pub fn git(path: &str, args: &[&str]) -> Result<String, String> {
+    debug!("git: in '{}' running {:?}", path, args);
+    let output = match Command::new("git").current_dir(path).args(args).output() {
+        Ok(output) => output,
+        Err(err) => return Err(format!("Unable to run git : {}", err)),
+    };
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(format!(
+            "git {} failed : {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+pub fn is_repository(path: &str) -> bool {
+    git(path, &["rev-parse", "--is-inside-work-tree"]).is_ok()
+}
+
+/// Initialize a repository in `path` and commit the project to it. A path
+/// inside an existing repository is left alone, so nothing is committed to a
+/// parent project. Returns whether a repository was created.
+pub fn init(path: &str) -> Result<bool, String> {
+    if is_repository(path) {
+        info!("git: '{}' is already in a repository", path);
+        return Ok(false);
+    }
+    git(path, &["init"])?;
+    commit(path, "Initial commit")?;
+    Ok(true)
+}
+
+pub fn commit(path: &str, message: &str) -> Result<(), String> {
+    git(path, &["add", "--all", "."])?;
+    git(path, &["commit", "-m", message])?;
+    Ok(())
+}